@@ -1,7 +1,8 @@
 use poise::CreateReply;
 use serenity::all::{CreateAttachment, Message};
 
-use crate::{colors::NordOptions, fetch_image_and_info, process_attachments, tickbox::TickBox, AsyncError, Context};
+use crate::{apply_user_preferences, colors::NordOptions, erased_color_swatches, fetch_image_and_info, process_attachments, tickbox::TickBox, AsyncError, Context};
+use crate::storage::GuildSettings;
 
 /// Show this help menu
 #[poise::command(prefix_command, track_edits, slash_command)]
@@ -25,6 +26,43 @@ pub async fn help(
 
 
 
+/// Change when this server is asked to darken images
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD", default_member_permissions = "MANAGE_GUILD")]
+pub async fn settings(
+    ctx: Context<'_>,
+    #[description = "Average brightness from which images are bright, between 0 and 1"]
+    #[min = 0.0]
+    #[max = 1.0]
+    brightness_threshold: Option<f32>,
+    #[description = "Whether bright images get the question to darken them"] ask_to_darken: Option<bool>,
+    #[description = "Use the threshold of the bot again"] reset_threshold: Option<bool>,
+) -> Result<(), AsyncError> {
+    let Some(storage) = &ctx.data().storage else {
+        ctx.send(CreateReply::default().content("Settings can't be saved, since no storage is configured").ephemeral(true)).await?;
+        return Ok(());
+    };
+    let guild_id = u64::from(ctx.guild_id().ok_or("Settings only exist for servers")?);
+    let mut settings: GuildSettings = storage.guilds().get(guild_id).await?;
+    if let Some(threshold) = brightness_threshold {
+        settings.brightness_threshold = Some(threshold);
+    }
+    if reset_threshold == Some(true) {
+        settings.brightness_threshold = None;
+    }
+    if let Some(ask_to_darken) = ask_to_darken {
+        settings.ask_to_darken = ask_to_darken;
+    }
+    storage.guilds().save(&settings).await?;
+    let threshold = settings.brightness_threshold.unwrap_or(ctx.data().config.threshold.brightness);
+    let content = format!(
+        "Images with an average brightness from {:.2} are {}",
+        threshold,
+        if settings.ask_to_darken { "answered with the question to darken them" } else { "left alone" },
+    );
+    ctx.send(CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
 #[poise::command(context_menu_command = "Edit Image", slash_command)]
 pub async fn edit_message_image(
     ctx: Context<'_>,
@@ -40,19 +78,23 @@ pub async fn edit_message_image(
     tickbox.next();
     reply.edit(ctx, CreateReply::default().content(&tickbox.to_string())).await?;
     let first_attachment = message.attachments.first().unwrap();
-    let (_image, info) = fetch_image_and_info(&first_attachment, ctx.data()).await?;
+    let info = fetch_image_and_info(&first_attachment, ctx.data()).await?.info;
     tickbox.next();
     reply.edit(ctx, CreateReply::default().content(&tickbox.to_string())).await?;
     let mut options = NordOptions::from_image_information(&info);
     options.start = true;
-    let buffer = process_attachments(&message, ctx.data(), &options).await?;
+    apply_user_preferences(ctx.data(), ctx.author().id.into(), &mut options).await;
+    let (buffer, filename, _) = process_attachments(&message, ctx.data(), &options).await?;
     tickbox.next();
     reply.edit(ctx, CreateReply::default().content(&tickbox.to_string())).await?;
-    ctx.send(
-        CreateReply::default()
-            .attachment(CreateAttachment::bytes(buffer, &first_attachment.filename))
-            .components(options.build_componets(u64::from(message.id), true))
-    ).await?;
+    let mut response = CreateReply::default()
+        .attachment(CreateAttachment::bytes(buffer, filename))
+        .components(options.build_componets(u64::from(message.id), true));
+    // show which colors the eraser picked
+    if let Some((swatches, description)) = erased_color_swatches(&info, &options) {
+        response = response.attachment(swatches).content(description);
+    }
+    ctx.send(response).await?;
     reply.delete(ctx).await?;
     Ok(())
 }
@@ -1,10 +1,32 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use toml;
 
+use crate::storage::StorageKind;
+use crate::utils::image_processing::{FilterStep, OutputFormat};
+use crate::utils::models::ModelConfig;
+
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub threshold: ThresholdConfig,
+    /// Pipelines which replace the built-in ones of `NordPreset`, keyed by `NordPreset::name`
+    #[serde(default)]
+    pub presets: HashMap<String, Vec<FilterStep>>,
+    #[serde(default)]
+    pub animation: AnimationConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub inference: InferenceConfig,
+    #[serde(default)]
+    pub images: ImageCacheConfig,
+    #[serde(default)]
+    pub results: ResultCacheConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -13,6 +35,135 @@ pub struct ThresholdConfig {
     pub modelpath: String,
 }
 
+/// Limits for animated GIFs and WebPs. Bigger animations are rejected.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct AnimationConfig {
+    pub max_frames: usize,
+    /// Sum of the pixels of all frames
+    pub max_pixels: u64,
+}
+
+impl Default for AnimationConfig {
+    fn default() -> Self {
+        AnimationConfig {
+            max_frames: 300,
+            max_pixels: 100_000_000,
+        }
+    }
+}
+
+/// How results are encoded, unless the user picks something else
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    /// 1-100, used by lossy formats only
+    pub quality: u8,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: OutputFormat::Original,
+            quality: 90,
+        }
+    }
+}
+
+/// How many decoded attachments are kept for the buttons below them
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ImageCacheConfig {
+    /// Megabytes of decoded pixels
+    pub memory_mb: usize,
+}
+
+impl Default for ImageCacheConfig {
+    fn default() -> Self {
+        ImageCacheConfig {
+            memory_mb: 512,
+        }
+    }
+}
+
+/// Where encoded results are kept to answer repeated requests right away
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ResultCacheConfig {
+    /// Megabytes of results kept in memory
+    pub memory_mb: usize,
+    /// Directory which keeps results across restarts, nothing is written to disk without it
+    pub directory: Option<String>,
+    /// Megabytes the directory may take, the files written first are deleted beyond it. 0 keeps every file
+    pub directory_mb: u64,
+}
+
+impl Default for ResultCacheConfig {
+    fn default() -> Self {
+        ResultCacheConfig {
+            memory_mb: 256,
+            directory: None,
+            directory_mb: 2048,
+        }
+    }
+}
+
+/// The database which keeps results and settings across restarts
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub kind: StorageKind,
+    /// Path of the SQLite file or Postgres connection url, `DATABASE_URL` takes precedence
+    pub url: Option<String>,
+    /// Most connections to Postgres at once
+    pub pool_size: usize,
+    /// Downloaded images and results which weren't used for longer are deleted, 0 keeps them forever
+    pub results_max_age_days: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            kind: StorageKind::None,
+            url: None,
+            pool_size: 8,
+            results_max_age_days: 30,
+        }
+    }
+}
+
+/// Threads which run the background removal models
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct InferenceConfig {
+    /// Every worker keeps its own copy of each model it used
+    pub workers: usize,
+    /// Images which may wait for a worker before new requests block
+    pub queue_size: usize,
+    /// Load all models at startup instead of on first use
+    pub warm_up: bool,
+    /// Most tiles a single image is split into for high detail segmentation
+    pub max_tiles: usize,
+    /// Fraction of a tile which overlaps with its neighbours
+    pub tile_overlap: f32,
+    /// Megabytes of segmentation masks which are kept to skip the model when only other options change
+    pub mask_cache_mb: usize,
+}
+
+impl Default for InferenceConfig {
+    fn default() -> Self {
+        InferenceConfig {
+            workers: 2,
+            queue_size: 16,
+            warm_up: false,
+            max_tiles: 16,
+            tile_overlap: 0.25,
+            mask_cache_mb: 128,
+        }
+    }
+}
+
 pub fn load_config() -> Config {
     // Include the contents of config.toml at compile time
     // pwd:
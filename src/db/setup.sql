@@ -1,5 +0,0 @@
--- Create the table
-CREATE TABLE images (
-    id TEXT PRIMARY KEY,
-    image_oid OID NOT NULL
-);
\ No newline at end of file
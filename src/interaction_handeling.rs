@@ -1,6 +1,7 @@
-use serenity::all::{ComponentInteraction, CreateAttachment, CreateInteractionResponse, CreateInteractionResponseFollowup, EditAttachments, EditInteractionResponse, Message, ModalInteraction};
-use anyhow::Result;
-use crate::{colors::{NordOptions, RgbColor}, fetch_image, fetch_or_raise_message, modal_get_color, process_attachments, AnyInteraction, Data, SContext};
+use serenity::all::{ComponentInteraction, CreateAttachment, CreateInteractionResponse, CreateInteractionResponseFollowup, CreateInteractionResponseMessage, EditAttachments, EditInteractionResponse, Message, ModalInteraction};
+use anyhow::{bail, Result};
+use log::warn;
+use crate::{colors::{NordOptions, RgbColor}, erased_color_swatches, fetch_image_and_info, fetch_or_raise_message, modal_get_background_image, modal_get_color, modal_get_palette, modal_get_cleanup, modal_get_mask_tuning, modal_get_tuning, process_attachments, save_user_preferences, utils::{backgrounds::BACKGROUND_IMAGE_REQUEST, palette::PALETTE_REQUEST}, AnyInteraction, Data, SContext};
 
 
 /// Handles an interaction starting with dark-
@@ -12,9 +13,19 @@ pub async fn handle_interaction_darkening(
 ) -> Result<()> {
     data.question_messages.lock().await.remove(&interaction.message.id.into());
     let content = &interaction.data.custom_id;
-    let mut options = NordOptions::from_custom_id(&content);
     let message_id = content.split("-").last().unwrap().parse::<u64>()?;
-    let _update = content.split("-").nth(1).unwrap().parse::<bool>().unwrap_or(true);
+    let mut options = match NordOptions::from_custom_id(&content) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Failed to read options from custom id: {}", e);
+            let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+                .content("These buttons are outdated. Please ask me again to darken the image.")
+                .ephemeral(true)
+            );
+            interaction.create_response(&ctx, response).await?;
+            return Ok(());
+        }
+    };
 
     let mut current_interaction = AnyInteraction::Component(interaction.clone());
 
@@ -32,13 +43,91 @@ pub async fn handle_interaction_darkening(
         options.background_color = Some(color);
         current_interaction = AnyInteraction::Modal(new_interaction);
     }
+
+    // ask for a background image
+    if options.background_image == BACKGROUND_IMAGE_REQUEST {
+        let (id, new_interaction) = match modal_get_background_image(ctx, interaction, &data.backgrounds).await {
+            Ok(image) => image,
+            Err(_) => {
+                // Error handled inside modal_get_background_image
+                return Ok(());
+            }
+        };
+        options.background_image = id;
+        current_interaction = AnyInteraction::Modal(new_interaction);
+    }
+
+    // ask for a custom palette
+    if options.palette == PALETTE_REQUEST {
+        let (palette, new_interaction) = match modal_get_palette(ctx, interaction).await {
+            Ok(palette) => palette,
+            Err(_) => {
+                // Error handled inside modal_get_palette
+                return Ok(());
+            }
+        };
+        options.palette = data.palettes.insert(palette).await;
+        current_interaction = AnyInteraction::Modal(new_interaction);
+    }
+
+    // ask for filter strengths
+    if options.tune {
+        let (tuned, new_interaction) = match modal_get_tuning(ctx, interaction, &options).await {
+            Ok(tuned) => tuned,
+            Err(_) => {
+                // Error handled inside modal_get_tuning
+                return Ok(());
+            }
+        };
+        options = tuned;
+        current_interaction = AnyInteraction::Modal(new_interaction);
+    }
+
+    // ask for the mask activation parameters
+    if options.tune_mask {
+        let (tuned, new_interaction) = match modal_get_mask_tuning(ctx, interaction, &options).await {
+            Ok(tuned) => tuned,
+            Err(_) => {
+                // Error handled inside modal_get_mask_tuning
+                return Ok(());
+            }
+        };
+        options = tuned;
+        current_interaction = AnyInteraction::Modal(new_interaction);
+    }
+
+    // ask for the mask cleanup steps
+    if options.tune_cleanup {
+        let (tuned, new_interaction) = match modal_get_cleanup(ctx, interaction, &options).await {
+            Ok(tuned) => tuned,
+            Err(_) => {
+                // Error handled inside modal_get_cleanup
+                return Ok(());
+            }
+        };
+        options = tuned;
+        current_interaction = AnyInteraction::Modal(new_interaction);
+    }
     let mut message: Option<Message> = None;
 
     // auto adjust options
     if options.auto_adjust {
         message = Some(fetch_or_raise_message(&ctx, &interaction, message_id).await);
         let ref unwrapped = message.as_ref().unwrap();
-        let (_image, information) = fetch_image(unwrapped.attachments.first().unwrap(), data).await;
+        let Some(attachment) = unwrapped.attachments.first() else {
+            bail!("The message has no image anymore");
+        };
+        let information = match fetch_image_and_info(attachment, data).await {
+            Ok(cached) => cached.info,
+            Err(e) => {
+                let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+                    .content(format!("I can't read the image: {}", e))
+                    .ephemeral(true)
+                );
+                current_interaction.create_response(&ctx, response).await?;
+                return Ok(());
+            }
+        };
         let new_options = NordOptions::from_image_information(&information);
         options = NordOptions {start: options.start, ..new_options};
     }
@@ -59,7 +148,7 @@ pub async fn handle_interaction_darkening(
             .attachments(EditAttachments::keep_all(&interaction.message))
             .content("⌛ I'm working on it. Please wait a moment.")
             .components(new_components.clone());
-        current_interaction.edit_response(&ctx, response).await.unwrap();
+        current_interaction.edit_response(&ctx, response).await?;
     } else {
         // first ack, that existing image is being kept
         let response = CreateInteractionResponse::Acknowledge;
@@ -69,7 +158,7 @@ pub async fn handle_interaction_darkening(
             .attachments(EditAttachments::keep_all(&interaction.message))
             .content("⌛ I change the options. Please wait a moment.")
             .components(new_components.clone());
-        current_interaction.edit_response(&ctx, response).await.unwrap();
+        current_interaction.edit_response(&ctx, response).await?;
     }
     
     if !options.start {
@@ -89,25 +178,49 @@ pub async fn handle_interaction_darkening(
     }
     let message = message.unwrap();
     // process image
-    let buffer = match process_attachments(&message, &data, &options).await {
-        Ok(buffer) => buffer,
+    let (buffer, filename, information) = match process_attachments(&message, &data, &options).await {
+        Ok(result) => result,
         Err(e) => {
             current_interaction.edit_response(&ctx, EditInteractionResponse::default().content(e.to_string())).await?;
             return Ok(())
         }
     };
-    let attachment = CreateAttachment::bytes(buffer, "image.webp");
-    let content = EditInteractionResponse::new()
+    save_user_preferences(data, interaction.user.id.into(), &options).await;
+    let attachment = CreateAttachment::bytes(buffer, filename);
+    let mut content = EditInteractionResponse::new()
         .new_attachment(attachment)
         .content("Here it is! May I delete your shiny one?")
         .components(new_components.clone())
     ;
+    // show which colors the eraser picked
+    if let Some((swatches, description)) = erased_color_swatches(&information, &options) {
+        content = content
+            .new_attachment(swatches)
+            .content(format!("Here it is! May I delete your shiny one?\n{}", description));
+    }
     // stone emoji: 
     println!("sending message");
     current_interaction.edit_response(&ctx, content).await?;
     Ok(())
 }
 
+/// Tells the user that handling their click failed. The interaction may be answered already,
+/// then the error is sent as a followup.
+pub async fn report_error(ctx: &SContext, interaction: &ComponentInteraction, error: &anyhow::Error) {
+    let content = format!("Something went wrong: {}", error);
+    let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+        .content(&content)
+        .ephemeral(true)
+    );
+    if interaction.create_response(&ctx, response).await.is_ok() {
+        return;
+    }
+    let followup = CreateInteractionResponseFollowup::new().content(content).ephemeral(true);
+    if let Err(e) = interaction.create_followup(&ctx, followup).await {
+        warn!("Failed to tell the user about the error: {}", e);
+    }
+}
+
 /// handeles interactions starting with delete-
 /// which will delete the message_id which is contained in the custom_id
 pub async fn handle_dispose(ctx: &SContext, interaction: &ComponentInteraction, message_id: u64) -> Result<()> {
@@ -1,24 +1,25 @@
 #![warn(clippy::str_to_string)]
 mod commands;
 use colors::{ImageInformation, NordOptions, RgbColor};
-use config::Config;
+use config::{AnimationConfig, Config};
 use poise::serenity_prelude as serenity;
 use dotenv::dotenv;
 use ::serenity::all::{
-    Attachment, ButtonStyle, CacheHttp, ComponentInteraction, CreateAttachment, CreateButton, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, CreateQuickModal, EditInteractionResponse, Interaction, Message, ModalInteraction, ReactionType
+    Attachment, ButtonStyle, CacheHttp, ComponentInteraction, CreateAttachment, CreateButton, CreateInputText, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, CreateQuickModal, EditInteractionResponse, InputTextStyle, Interaction, Message, ModalInteraction, ReactionType
 };
 use std::{
     env, io::Cursor, sync::{Arc}, time::Duration
 };
 use anyhow::{bail, Result};
 use reqwest;
-use image::DynamicImage;
+use bytes::Bytes;
+use image::{DynamicImage, ImageError, ImageFormat};
 
 // Types used by all command functions
 type AsyncError = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, AsyncError>;
 type SContext = serenity::Context;
-use log::{info, warn};
+use log::{debug, info, warn};
 use tokio::sync::Mutex;
 use std::collections::HashSet;
 
@@ -26,18 +27,31 @@ mod config;
 mod tickbox;
 mod visual_scale;
 mod interaction_handeling;
+pub mod storage;
 
 pub mod utils;
-use utils::image_cache::ImageCache;
+use utils::image_cache::{CachedImage, ImageCache};
+use utils::model_manager::ModelManager;
+use utils::backgrounds::BackgroundStore;
+use utils::result_cache::{ResultCache, ResultKey};
+use storage::{calculate_sha256, GuildSettings, Storage, UserPreferences};
+use utils::palette::{Palette, PaletteStore, CUSTOM_PALETTE_START};
 use utils::colors;
 use utils::generate_tp_image;
+use utils::image_processing::{decode_image, encode, output_filename, Animation, BackgroundMode, MaskCleanup, OutputFormat};
+use utils::models::BackgroundModel;
 // Custom user data passed to all command functions
 
 
 
 pub struct Data {
     image_cache: ImageCache,
+    palettes: PaletteStore,
+    backgrounds: BackgroundStore,
+    results: ResultCache,
+    storage: Option<Storage>,
     config: Config,
+    models: ModelManager,
     question_messages: Mutex<HashSet<u64>>,
 }
 
@@ -63,7 +77,10 @@ async fn interaction_create(ctx: SContext, interaction: Interaction, data: &Data
     if let Interaction::Component(interaction) = interaction {
         let content = &interaction.data.custom_id;
         if content.starts_with("darken-") {
-            interaction_handeling::handle_interaction_darkening(&ctx, &interaction, data).await.unwrap();
+            if let Err(e) = interaction_handeling::handle_interaction_darkening(&ctx, &interaction, data).await {
+                warn!("Failed to darken the image of {}: {}", content, e);
+                interaction_handeling::report_error(&ctx, &interaction, &e).await;
+            }
         }
         if content.starts_with("delete-") {
             let message_id = content.split("-").last().unwrap().parse::<u64>().unwrap();
@@ -101,8 +118,9 @@ async fn modal_get_color(ctx: &SContext, interaction: &ComponentInteraction) ->
     let modal = CreateQuickModal::new("Enter a Color")
         .timeout(std::time::Duration::from_secs(600))
         .short_field("Color (hex) e.g. #AF4453");
-    let response = interaction.quick_modal(ctx, modal).await?;
-    let response = response.unwrap(); 
+    let Some(response) = interaction.quick_modal(ctx, modal).await? else {
+        bail!("The color modal was closed without an answer");
+    };
     let color_code = &response.inputs[0];
     let color = match RgbColor::from_hex(&color_code) {
         Ok(color) => {
@@ -122,6 +140,134 @@ async fn modal_get_color(ctx: &SContext, interaction: &ComponentInteraction) ->
     Ok(color)
 }
 
+// Downloads the image behind the Discord link the user entered and returns its id, or err
+async fn modal_get_background_image(ctx: &SContext, interaction: &ComponentInteraction, backgrounds: &BackgroundStore) -> Result<(u16, ModalInteraction)> {
+    let modal = CreateQuickModal::new("Choose a Background Image")
+        .timeout(std::time::Duration::from_secs(600))
+        .short_field("Link of an image uploaded to Discord (Copy Link)");
+    let Some(response) = interaction.quick_modal(ctx, modal).await? else {
+        bail!("The background image modal was closed without an answer");
+    };
+    let url = response.inputs[0].trim();
+    match backgrounds.download(url).await {
+        Ok(id) => Ok((id, response.interaction)),
+        Err(e) => {
+            response
+                .interaction
+                .create_response(ctx, CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+                    .content(format!("Could not load the background image: {}", e))
+                ))
+                .await?;
+            bail!("Could not load the background image: {}", e);
+        }
+    }
+}
+
+// Returns the palette the user entered, or err
+async fn modal_get_palette(ctx: &SContext, interaction: &ComponentInteraction) -> Result<(Palette, ModalInteraction)> {
+    let modal = CreateQuickModal::new("Enter a Palette")
+        .timeout(std::time::Duration::from_secs(600))
+        .paragraph_field("Colors (hex) e.g. #1e1e2e,#313244,#89b4fa");
+    let Some(response) = interaction.quick_modal(ctx, modal).await? else {
+        bail!("The palette modal was closed without an answer");
+    };
+    let color_codes = &response.inputs[0];
+    match Palette::from_hex_list("Custom", color_codes) {
+        Ok(palette) => Ok((palette, response.interaction)),
+        Err(e) => {
+            response
+                .interaction
+                .create_response(ctx, CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+                    .content(format!("Invalid palette: {}", e))
+                )).await?;
+            bail!("Invalid palette: {}", e);
+        }
+    }
+}
+
+/// A number field of a modal: label, current value, smallest and biggest accepted value
+type NumberField<'a> = (&'a str, f32, f32, f32);
+
+/// Asks for numbers in a modal whose fields are prefilled with the current values.
+/// Returns the numbers in the order of the fields, or err if the modal was closed or a number is out of range,
+/// which is answered on the modal.
+async fn modal_get_numbers<const N: usize>(
+    ctx: &SContext,
+    interaction: &ComponentInteraction,
+    title: &str,
+    fields: [NumberField<'_>; N],
+) -> Result<([f32; N], ModalInteraction)> {
+    let modal = fields.iter().fold(
+        CreateQuickModal::new(title).timeout(std::time::Duration::from_secs(600)),
+        |modal, (label, value, _, _)| modal.field(CreateInputText::new(InputTextStyle::Short, *label, "").value(format!("{}", value))),
+    );
+    let Some(response) = interaction.quick_modal(ctx, modal).await? else {
+        bail!("The {} modal was closed without an answer", title);
+    };
+    let mut numbers = [0f32; N];
+    for (index, (_, _, min, max)) in fields.into_iter().enumerate() {
+        let input = response.inputs[index].trim();
+        match input.parse::<f32>() {
+            Ok(value) if (min..=max).contains(&value) => numbers[index] = value,
+            _ => {
+                let message = format!("Invalid value: `{}` is not a number between {} and {}", input, min, max);
+                response
+                    .interaction
+                    .create_response(ctx, CreateInteractionResponse::Message(CreateInteractionResponseMessage::new()
+                        .content(&message)
+                    )).await?;
+                bail!(message);
+            }
+        }
+    }
+    Ok((numbers, response.interaction))
+}
+
+// Returns `options` with the strengths the user entered, or err
+async fn modal_get_tuning(ctx: &SContext, interaction: &ComponentInteraction, options: &NordOptions) -> Result<(NordOptions, ModalInteraction)> {
+    let ([hue_rotate, invert_strength, sepia_strength, nord_strength, erase_distance], interaction) = modal_get_numbers(ctx, interaction, "Tune Strengths", [
+        ("Hue Rotation (0 - 360 degrees)", options.hue_rotate, 0., 360.),
+        ("Invert Strength (0 - 1)", options.invert_strength, 0., 1.),
+        ("Sepia Strength (0 - 1)", options.sepia_strength, 0., 1.),
+        ("Palette Blend (0 - 1)", options.nord_strength, 0., 1.),
+        ("Erase Distance (0 - 255)", options.erase_distance, 0., 255.),
+    ]).await?;
+    let tuned = NordOptions {
+        hue_rotate: hue_rotate % 360.,
+        invert_strength,
+        sepia_strength,
+        nord_strength,
+        erase_distance,
+        tune: false,
+        ..options.clone()
+    };
+    Ok((tuned, interaction))
+}
+
+/// Asks for the center and steepness of the mask activation function and the blend of the hybrid eraser,
+/// prefilled with the current values
+async fn modal_get_mask_tuning(ctx: &SContext, interaction: &ComponentInteraction, options: &NordOptions) -> Result<(NordOptions, ModalInteraction)> {
+    let ([mask_center, mask_steepness, fusion_weight], interaction) = modal_get_numbers(ctx, interaction, "Tune Mask", [
+        ("Center (0 - 1)", options.mask_center, 0., 1.),
+        ("Steepness (0.1 - 25)", options.mask_steepness, 0.1, 25.),
+        ("Model share of the hybrid blend (0 - 1)", options.fusion_weight, 0., 1.),
+    ]).await?;
+    let tuned = NordOptions { mask_center, mask_steepness, fusion_weight, tune_mask: false, ..options.clone() };
+    Ok((tuned, interaction))
+}
+
+/// Asks for the mask cleanup steps, prefilled with the current values. Shares are entered in percent.
+async fn modal_get_cleanup(ctx: &SContext, interaction: &ComponentInteraction, options: &NordOptions) -> Result<(NordOptions, ModalInteraction)> {
+    let ([min_island, max_hole, grow, feather], interaction) = modal_get_numbers(ctx, interaction, "Clean Mask", [
+        ("Remove islands below (% of image, 0 - 25)", options.cleanup.min_island * 100., 0., 25.),
+        ("Fill holes below (% of image, 0 - 25)", options.cleanup.max_hole * 100., 0., 25.),
+        ("Grow (+) or shrink (-) in pixels (-20 - 20)", options.cleanup.grow as f32, -20., 20.),
+        ("Feather in pixels (0 - 25)", options.cleanup.feather, 0., 25.),
+    ]).await?;
+    let cleanup = MaskCleanup { min_island: min_island / 100., max_hole: max_hole / 100., grow: grow.round() as i8, feather };
+    Ok((NordOptions { cleanup, tune_cleanup: false, ..options.clone() }, interaction))
+}
+
 enum AnyInteraction {
     Component(ComponentInteraction),
     Modal(ModalInteraction),
@@ -162,34 +308,117 @@ impl AnyInteraction {
 
 
 
-pub async fn process_attachments(message: &Message, data: &Data, options: &NordOptions) -> Result<Vec<u8>, AsyncError>{
-    for attachment in &message.attachments {
-        println!("Processing attachment");
-        let image = process_image(&attachment, data, options.clone()).await.unwrap();
-        println!("writing image to buffer");
-        let mut buffer = Vec::new();
-            // Create a PNG encoder with a specific compression level
-        {
-            let mut cursor = Cursor::new(&mut buffer);
-            //let encoder = PngEncoder::new_with_quality(&mut cursor, CompressionType::Fast, FilterType::Adaptive);
-            image.write_to( &mut cursor, image::ImageFormat::WebP).expect("Failed to write image to buffer");
-            //encoder.write_image(&image.as_bytes(), image.width(), image.height(), image::ExtendedColorType::Rgba8).unwrap();
+/// The colors which the dominant color eraser removed, as an attachment of swatches and a line for the message.
+/// None if the options don't erase colors. The swatches only explain the result, so failing to draw them is only logged.
+pub fn erased_color_swatches(info: &ImageInformation, options: &NordOptions) -> Option<(CreateAttachment, String)> {
+    match draw_erased_colors(info, options) {
+        Ok(swatches) => swatches,
+        Err(e) => {
+            warn!("Failed to draw the erased colors: {}", e);
+            None
+        }
+    }
+}
+
+fn draw_erased_colors(info: &ImageInformation, options: &NordOptions) -> Result<Option<(CreateAttachment, String)>> {
+    if !options.erase_most_present_color || !matches!(options.model, BackgroundModel::Algorithm | BackgroundModel::Hybrid(_)) {
+        return Ok(None);
+    }
+    let colors: Vec<RgbColor> = colors::erased_colors(info, options).into_iter().map(|(color, _)| color).collect();
+    if colors.is_empty() {
+        return Ok(None);
+    }
+    let swatches = encode(&DynamicImage::ImageRgba8(colors::swatch_image(&colors)), OutputFormat::Png, 100)?;
+    let description = colors.iter().map(|color| format!("`{}`", color.as_hex())).collect::<Vec<_>>().join(" ");
+    Ok(Some((CreateAttachment::bytes(swatches, "erased_colors.png"), format!("Erased colors: {}", description))))
+}
+
+/// Applies the options to the first attachment and returns the encoded result with its filename
+/// and the information of the attachment.
+/// Animations are processed frame by frame and sent back as WebP when a WebP output format is chosen,
+/// otherwise as GIF. Still images are sent back in the chosen output format.
+/// The filename keeps the stem of the attachment, including a `SPOILER_` prefix.
+/// Results of earlier requests with the same image and options are sent again.
+pub async fn process_attachments(message: &Message, data: &Data, options: &NordOptions) -> Result<(Vec<u8>, String, Arc<ImageInformation>), AsyncError>{
+    if let Some(attachment) = message.attachments.first() {
+        let cached = fetch_image_and_info(attachment, data).await?;
+        let info = cached.info.clone();
+        let key = result_key(&cached, data, options).await;
+        if let Some(key) = &key {
+            if let Some(buffer) = data.results.get(key).await {
+                debug!("Sending the cached result");
+                // the result knows its format, which may differ from the source, e.g. for animations
+                let extension = image::guess_format(&buffer).map_or("png", |format| format.extensions_str()[0]);
+                return Ok((buffer.to_vec(), output_filename(&attachment.filename, extension), info));
+            }
         }
-        return Ok(buffer);
+        let (buffer, filename) = process_attachment(attachment, cached, data, options).await?;
+        if let Some(key) = key {
+            data.results.insert(key, Arc::new(buffer.clone())).await;
+        }
+        return Ok((buffer, filename, info));
+    }
+    Err("No attachment found in message".into())
+}
+
+/// Key of the result of the attachment with the options, `None` if the result can't be cached
+async fn result_key(cached: &CachedImage, data: &Data, options: &NordOptions) -> Option<ResultKey> {
+    // uploaded backgrounds are only known by their id, which is given out again after a restart
+    if options.background_color.is_some() && options.background_mode == BackgroundMode::Image {
+        return None;
     }
-    panic!("No attachment found in message");
+    let palette = data.palettes.get(options.palette).await;
+    let format = options.output_format.unwrap_or(data.config.output.format);
+    let quality = options.quality.unwrap_or(data.config.output.quality);
+    Some(data.results.key(&cached.source_hash, options, &palette, format, quality))
+}
+
+/// Applies the options to the decoded attachment and encodes the result
+async fn process_attachment(attachment: &Attachment, cached: CachedImage, data: &Data, options: &NordOptions) -> Result<(Vec<u8>, String), AsyncError>{
+    let source_format = attachment.content_type.as_deref().and_then(ImageFormat::from_mime_type);
+    let format = options.output_format.unwrap_or(data.config.output.format).resolve(source_format);
+    let quality = options.quality.unwrap_or(data.config.output.quality);
+    if let Some(animation) = decode_animation(&cached, &data.config.animation).await? {
+        debug!("Processing animation with {} frames", animation.frames.len());
+        let info = cached.info.clone();
+        let palette = data.palettes.get(options.palette).await;
+        let backdrop = data.backgrounds.get(options.background_image).await;
+        let options = options.clone();
+        let models = data.models.clone();
+        let pipeline = colors::select_pipeline(&options, &data.config.presets);
+        // the information of the first frame is used for all frames, so that they are all treated the same
+        // the frames share the hash of the file, so their masks can't be cached by it
+        let buffer = tokio::task::spawn_blocking(move || {
+            let animation = animation
+                .map_frames(|frame| colors::apply_nord(frame, None, options.clone(), &pipeline, &info, &palette, &models, backdrop.as_deref()))?;
+            // only WebP keeps an animation in one of the output formats, everything else becomes a GIF
+            match format {
+                OutputFormat::WebpLossless => animation.encode_webp(None),
+                OutputFormat::WebpLossy => animation.encode_webp(Some(quality)),
+                _ => animation.encode_gif(),
+            }
+        }).await??;
+        let extension = if matches!(format, OutputFormat::WebpLossless | OutputFormat::WebpLossy) { "webp" } else { "gif" };
+        return Ok((buffer, output_filename(&attachment.filename, extension)));
+    }
+    debug!("Processing attachment");
+    let image = process_image(cached, data, options.clone()).await?;
+    debug!("writing image to buffer as {:?}", format);
+    // encoding takes a while for big images, so it runs next to the filters on a blocking thread
+    let buffer = tokio::task::spawn_blocking(move || encode(&image, format, quality)).await??;
+    Ok((buffer, output_filename(&attachment.filename, format.extension())))
 }
 
 
 #[tokio::main]
 async fn main() {
-    // env_logger::init();
     dotenv().ok();
+    // timings and cache decisions are logged at debug level, RUST_LOG=midna=debug shows them
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("midna=info")).init();
     // FrameworkOptions contains all of poise's configuration option in one struct
     // Every option can be omitted to use its default value
-    let image_cache = ImageCache::new(20, Duration::from_secs(600));
     let options = poise::FrameworkOptions {
-        commands: vec![commands::edit_message_image(), commands::help()],
+        commands: vec![commands::edit_message_image(), commands::settings(), commands::help()],
         prefix_options: poise::PrefixFrameworkOptions {
             prefix: Some("~".into()),
             edit_tracker: Some(Arc::new(poise::EditTracker::for_timespan(
@@ -238,9 +467,23 @@ async fn main() {
             Box::pin(async move {
                 println!("Logged in as {}", _ready.user.name);
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                let config = config::load_config();
+                // the bot works without storage, it only forgets more
+                let storage = match Storage::connect(&config.storage).await {
+                    Ok(storage) => storage,
+                    Err(e) => {
+                        warn!("Failed to connect to the storage, continuing without it: {}", e);
+                        None
+                    }
+                };
                 Ok(Data {
-                    image_cache: image_cache,
-                    config: config::load_config(),
+                    image_cache: ImageCache::new(config.images.memory_mb * 1024 * 1024),
+                    palettes: PaletteStore::default(),
+                    backgrounds: BackgroundStore::default(),
+                    results: ResultCache::new(&config, storage.clone()),
+                    storage,
+                    models: ModelManager::new(&config.inference),
+                    config,
                     question_messages: Mutex::new(HashSet::new()),
                 })
             })
@@ -301,6 +544,51 @@ async fn event_handler(
 }
 
 
+/// Settings of the guild from the storage, `None` in DMs or without storage
+async fn guild_settings(data: &Data, guild_id: Option<serenity::GuildId>) -> Option<GuildSettings> {
+    let (storage, guild_id) = (data.storage.as_ref()?, u64::from(guild_id?));
+    match storage.guilds().get(guild_id).await {
+        Ok(settings) => Some(settings),
+        Err(e) => {
+            warn!("Failed to read the settings of guild {}: {}", guild_id, e);
+            None
+        }
+    }
+}
+
+/// Applies what the user picked last to the options
+pub async fn apply_user_preferences(data: &Data, user_id: u64, options: &mut NordOptions) {
+    let Some(storage) = &data.storage else {
+        return;
+    };
+    match storage.users().get(user_id).await {
+        Ok(Some(preferences)) => {
+            options.palette = preferences.palette;
+            options.output_format = preferences.output_format;
+            options.quality = preferences.quality;
+        },
+        Ok(None) => {},
+        Err(e) => warn!("Failed to read the preferences of user {}: {}", user_id, e),
+    }
+}
+
+/// Remembers the palette and output settings of the options for the user
+pub async fn save_user_preferences(data: &Data, user_id: u64, options: &NordOptions) {
+    let Some(storage) = &data.storage else {
+        return;
+    };
+    let preferences = UserPreferences {
+        user_id,
+        // custom palettes are forgotten on restart, so their ids can't be kept
+        palette: if options.palette < CUSTOM_PALETTE_START { options.palette } else { 0 },
+        output_format: options.output_format,
+        quality: options.quality,
+    };
+    if let Err(e) = storage.users().save(&preferences).await {
+        warn!("Failed to save the preferences of user {}: {}", user_id, e);
+    }
+}
+
 async fn image_check(attachment: &Attachment) -> Result<()> {
     let mib = attachment.size as f64 / 1024.0 / 1024.0;
     if mib > 16.0 {
@@ -317,21 +605,47 @@ async fn image_check(attachment: &Attachment) -> Result<()> {
 }
 
 
-pub async fn fetch_image_and_info(attachment: &Attachment, data: &Data) -> Result<(DynamicImage, ImageInformation)> {
+/// Returns the decoded attachment with its information from the cache, or downloads, analyzes and caches it
+pub async fn fetch_image_and_info(attachment: &Attachment, data: &Data) -> Result<CachedImage> {
     image_check(attachment).await?;
-    let url = attachment.url.clone();
-    let image_and_info = {
-        let image = data.image_cache.get(&url).await;
-        if image.is_none() {
-            let image = download_image(&attachment).await?;
-            Ok::<(DynamicImage, ImageInformation), anyhow::Error>(
-                (image.clone(), colors::calculate_average_brightness(&image.to_rgba8()))
-            )
-        } else {
-            Ok(image.unwrap())
+    let id = u64::from(attachment.id);
+    if let Some(cached) = data.image_cache.get(id).await {
+        return Ok(cached);
+    }
+    let (source_hash, bytes) = source_file(attachment, data).await?;
+    let image = Arc::new(decode_attachment(attachment, bytes.clone()).await?);
+    let info = Arc::new(analyze_image(image.clone()).await?);
+    // only GIFs and WebPs can be animated, the file of other images isn't needed anymore
+    let source = is_animation_candidate(attachment).then_some(bytes);
+    let cached = CachedImage { image, info, source_hash, source };
+    data.image_cache.insert(id, cached.clone()).await;
+    debug!("Image cache: {}", data.image_cache.stats().await);
+    Ok(cached)
+}
+
+
+/// The file of the attachment with its SHA-256, from the storage or downloaded.
+/// Downloaded files are put into the storage, so that they aren't downloaded again after a restart.
+async fn source_file(attachment: &Attachment, data: &Data) -> Result<(String, Bytes)> {
+    let id = u64::from(attachment.id);
+    if let Some(storage) = &data.storage {
+        match storage.images().load(id).await {
+            Ok(Some(file)) => return Ok(file),
+            Ok(None) => {},
+            Err(e) => warn!("Failed to read the attachment {} from the storage: {}", id, e),
         }
+    }
+    let bytes = download_attachment(attachment).await?;
+    let hash = {
+        let bytes = bytes.clone();
+        tokio::task::spawn_blocking(move || calculate_sha256(&bytes)).await?
     };
-    image_and_info
+    if let Some(storage) = &data.storage {
+        if let Err(e) = storage.images().store(id, &hash, &bytes).await {
+            warn!("Failed to write the attachment {} to the storage: {}", id, e);
+        }
+    }
+    Ok((hash, bytes))
 }
 
 
@@ -342,17 +656,20 @@ async fn ask_user_to_darken_image(
     data: &Data
 ) -> Result<(), anyhow::Error> {
 
+    let settings = guild_settings(data, message.guild_id).await;
+    if !settings.as_ref().is_none_or(|settings| settings.ask_to_darken) {
+        return Ok(());
+    }
+    let threshold = settings
+        .and_then(|settings| settings.brightness_threshold)
+        .unwrap_or(data.config.threshold.brightness);
+
     // download image or get from cache
     image_check(attachment).await?;
-    let url = attachment.url.clone();
-    let image_and_info = fetch_image_and_info(attachment, data).await;
-    let (image, info) = image_and_info
-        .expect("Image or info is none in ask_user_to_darken_image");
-    println!("inserting");
-    data.image_cache.insert(url, image.clone(), info.clone()).await;
+    let CachedImage { info, .. } = fetch_image_and_info(attachment, data).await?;
     let bright = info.brightness.average;
-    if bright < data.config.threshold.brightness {
-        panic!("Not bright enough: {bright}")
+    if bright < threshold {
+        return Ok(());
     }
     
     let start = std::time::Instant::now();
@@ -413,31 +730,62 @@ async fn ask_user_to_darken_image(
 }
 
 
-async fn fetch_image(
-    attachment: &serenity::Attachment, 
-    data: &Data, 
-) -> (DynamicImage, ImageInformation) {
-    image_check(attachment).await.unwrap();
-    let url = attachment.url.clone();
-    let image_and_info: std::result::Result<(DynamicImage, ImageInformation), anyhow::Error> = {
-        let image = data.image_cache.get(&url).await;
-        if image.is_none() {
-            let image = download_image(&attachment).await.unwrap();
-            Ok::<(DynamicImage, ImageInformation), anyhow::Error>(
-                (image.clone(), colors::calculate_average_brightness(&image.to_rgba8()))
-            )
-        } else {
-            Ok(image.unwrap())
-        }
+async fn process_image(cached: CachedImage, data: &Data, options: colors::NordOptions) -> Result<DynamicImage> {
+    let CachedImage { image, info, source_hash, .. } = cached;
+    let palette = data.palettes.get(options.palette).await;
+    let backdrop = data.backgrounds.get(options.background_image).await;
+    let models = data.models.clone();
+    let pipeline = colors::select_pipeline(&options, &data.config.presets);
+    // the filters keep every core busy, so they must not run on the threads of the async runtime.
+    // They consume the image, so the cached one is copied here and only here
+    let image = tokio::task::spawn_blocking(move || colors::apply_nord((*image).clone(), Some(&source_hash), options, &pipeline, &info, &palette, &models, backdrop.as_deref())).await??;
+    Ok(image)
+}
+
+/// Calculates the information of an image on a blocking thread
+async fn analyze_image(image: Arc<DynamicImage>) -> Result<ImageInformation> {
+    let info = tokio::task::spawn_blocking(move || colors::calculate_average_brightness(&image.to_rgba8())).await?;
+    Ok(info)
+}
+
+/// Whether the attachment is a GIF or WebP, which may be animated
+fn is_animation_candidate(attachment: &Attachment) -> bool {
+    matches!(attachment.content_type.as_deref(), Some("image/gif" | "image/webp"))
+}
+
+/// Decodes the kept file of a GIF or WebP frame by frame. Returns `None` if the image is not animated.
+async fn decode_animation(cached: &CachedImage, limits: &AnimationConfig) -> Result<Option<Animation>> {
+    let Some(bytes) = cached.source.clone() else {
+        return Ok(None);
     };
-    image_and_info.unwrap()
+    let limits = limits.clone();
+    tokio::task::spawn_blocking(move || Animation::decode(&bytes, &limits)).await?
+}
+
+/// Downloads the attachment as uploaded. The proxy url would convert animations into still images.
+async fn download_attachment(attachment: &Attachment) -> Result<Bytes> {
+    let response = reqwest::get(&attachment.url).await?;
+    if !response.status().is_success() {
+        info!("Request failed with status code: {}", response.status());
+        anyhow::bail!("Request failed with status code: {}", response.status());
+    }
+    let bytes = response.bytes().await?;
+    debug!("Downloaded attachment with {} bytes", bytes.len());
+    Ok(bytes)
 }
 
-async fn process_image(attachment: &serenity::Attachment, data: &Data, options: colors::NordOptions) -> Result<DynamicImage> {
-    let (image, info) = fetch_image(attachment, data).await;
-    Ok(colors::apply_nord(image, options, &info))
+/// Decodes the uploaded file on a blocking thread. Formats the decoders don't know are converted by the media proxy.
+async fn decode_attachment(attachment: &Attachment, bytes: Bytes) -> Result<DynamicImage> {
+    match tokio::task::spawn_blocking(move || decode_image(&bytes)).await? {
+        Err(e) if matches!(e.downcast_ref::<ImageError>(), Some(ImageError::Unsupported(_))) => {
+            debug!("Asking the media proxy for a PNG: {}", e);
+            download_image(attachment).await
+        },
+        result => result,
+    }
 }
 
+/// Downloads the attachment converted to PNG by the media proxy
 async fn download_image(attachment: &Attachment) -> Result<DynamicImage> {
     // Send the GET request
     //println!("Downloading: {}=&format=png", attachment.proxy_url);
@@ -450,13 +798,11 @@ async fn download_image(attachment: &Attachment) -> Result<DynamicImage> {
     }
    
     let bytes = response.bytes().await?;
-    // let raw = attachment.download().await?;
-    // Get the image bytes
     println!("Downloaded image with {} bytes", bytes.len());
     // Load the image from the bytes
-    let image = image::load_from_memory(&bytes).map_err(
+    let image = tokio::task::spawn_blocking(move || decode_image(&bytes)).await?.map_err(
         |e| anyhow::anyhow!("Failed to load image: {}", e)
     )?;
     
     Ok(image)
-}
\ No newline at end of file
+}
@@ -0,0 +1,41 @@
+-- Source images by the SHA-256 of their bytes
+CREATE TABLE images (
+    hash TEXT PRIMARY KEY,
+    data BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Images are evicted by their age, like results
+CREATE INDEX images_created_at ON images (created_at);
+
+-- Which image a Discord attachment holds, so that it isn't downloaded again.
+-- Evicting an image forgets its attachments
+CREATE TABLE attachments (
+    attachment_id BIGINT PRIMARY KEY,
+    hash TEXT NOT NULL REFERENCES images (hash) ON DELETE CASCADE
+);
+
+CREATE INDEX attachments_hash ON attachments (hash);
+
+-- Encoded results by the key of the result cache
+CREATE TABLE results (
+    hash TEXT PRIMARY KEY,
+    data BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Results are evicted by their age
+CREATE INDEX results_created_at ON results (created_at);
+
+CREATE TABLE guild_settings (
+    guild_id BIGINT PRIMARY KEY,
+    brightness_threshold REAL,
+    ask_to_darken BOOLEAN NOT NULL
+);
+
+CREATE TABLE user_preferences (
+    user_id BIGINT PRIMARY KEY,
+    palette INTEGER NOT NULL,
+    output_format SMALLINT,
+    quality SMALLINT
+);
@@ -0,0 +1,41 @@
+-- Source images by the SHA-256 of their bytes
+CREATE TABLE images (
+    hash TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Images are evicted by their age, like results
+CREATE INDEX images_created_at ON images (created_at);
+
+-- Which image a Discord attachment holds, so that it isn't downloaded again.
+-- Evicting an image forgets its attachments
+CREATE TABLE attachments (
+    attachment_id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL REFERENCES images (hash) ON DELETE CASCADE
+);
+
+CREATE INDEX attachments_hash ON attachments (hash);
+
+-- Encoded results by the key of the result cache
+CREATE TABLE results (
+    hash TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Results are evicted by their age
+CREATE INDEX results_created_at ON results (created_at);
+
+CREATE TABLE guild_settings (
+    guild_id INTEGER PRIMARY KEY,
+    brightness_threshold REAL,
+    ask_to_darken INTEGER NOT NULL
+);
+
+CREATE TABLE user_preferences (
+    user_id INTEGER PRIMARY KEY,
+    palette INTEGER NOT NULL,
+    output_format INTEGER,
+    quality INTEGER
+);
@@ -0,0 +1,339 @@
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::byte_lru::ByteLru;
+use crate::utils::image_processing::OutputFormat;
+use crate::utils::result_cache::ResultKey;
+
+pub mod postgres;
+pub mod sqlite;
+
+/// Schema migrations as (version, postgres script, sqlite script).
+/// Applied migrations are recorded in `schema_migrations`, new ones have to be appended.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, include_str!("migrations/postgres/0001_initial.sql"), include_str!("migrations/sqlite/0001_initial.sql")),
+];
+
+/// Users whose preferences are remembered, the least recently used ones are read from the database again
+const KNOWN_USERS: usize = 10_000;
+/// How often images and results which weren't used for `storage.results_max_age_days` are deleted
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Which database keeps images, results and settings
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    /// nothing is stored
+    #[default]
+    None,
+    /// a file, which needs no database server
+    Sqlite,
+    Postgres,
+}
+
+/// Tables which map a SHA-256 to bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlobTable {
+    Images,
+    Results,
+}
+
+impl BlobTable {
+    fn name(&self) -> &'static str {
+        match self {
+            BlobTable::Images => "images",
+            BlobTable::Results => "results",
+        }
+    }
+}
+
+/// Settings of a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuildSettings {
+    pub guild_id: u64,
+    /// overrides `threshold.brightness` of the config
+    pub brightness_threshold: Option<f32>,
+    /// whether bright images get the question to darken them
+    pub ask_to_darken: bool,
+}
+
+impl GuildSettings {
+    pub fn new(guild_id: u64) -> Self {
+        GuildSettings { guild_id, brightness_threshold: None, ask_to_darken: true }
+    }
+}
+
+/// What a user picked last, so that the next image starts with it
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserPreferences {
+    pub user_id: u64,
+    pub palette: u16,
+    pub output_format: Option<OutputFormat>,
+    pub quality: Option<u8>,
+}
+
+/// A database which can keep everything the repositories need
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Applies the migrations which are not applied yet
+    async fn migrate(&self) -> Result<()>;
+    /// The bytes of the hash, which counts as a use of them
+    async fn get_blob(&self, table: BlobTable, hash: &str) -> Result<Option<Vec<u8>>>;
+    /// Stores the bytes, keeping the existing ones if the hash is known already, which counts as a use of them
+    async fn put_blob(&self, table: BlobTable, hash: &str, data: &[u8]) -> Result<()>;
+    /// Deletes the bytes which weren't stored or used for `max_age` and returns how many were deleted
+    async fn delete_blobs_older_than(&self, table: BlobTable, max_age: Duration) -> Result<u64>;
+    /// The hash and the bytes of the image the attachment holds, which counts as a use of the image
+    async fn get_attachment(&self, attachment_id: u64) -> Result<Option<(String, Vec<u8>)>>;
+    /// Records which image the attachment holds, the image has to be stored already
+    async fn put_attachment(&self, attachment_id: u64, hash: &str) -> Result<()>;
+    async fn get_guild_settings(&self, guild_id: u64) -> Result<Option<GuildSettings>>;
+    async fn put_guild_settings(&self, settings: &GuildSettings) -> Result<()>;
+    async fn get_user_preferences(&self, user_id: u64) -> Result<Option<UserPreferences>>;
+    async fn put_user_preferences(&self, preferences: &UserPreferences) -> Result<()>;
+}
+
+/// The database the bot was configured with, accessed through its repositories.
+/// Cloning is cheap, all clones share the same connections.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    /// The preferences of the users which were read or saved last
+    users: Arc<Mutex<ByteLru<u64, UserPreferences>>>,
+}
+
+impl Storage {
+    /// Connects to the configured database and migrates it. `None` if storage is turned off.
+    /// The url of the config can be overridden with `DATABASE_URL`.
+    pub async fn connect(config: &crate::config::StorageConfig) -> Result<Option<Storage>> {
+        let url = std::env::var("DATABASE_URL").ok().or_else(|| config.url.clone());
+        let backend: Arc<dyn StorageBackend> = match (config.kind, url) {
+            (StorageKind::None, _) => return Ok(None),
+            (_, None) => bail!("Storage is enabled, but neither storage.url nor DATABASE_URL is set"),
+            (StorageKind::Sqlite, Some(path)) => Arc::new(sqlite::SqliteBackend::open(&path)?),
+            (StorageKind::Postgres, Some(url)) => Arc::new(postgres::PostgresBackend::connect(&url, config.pool_size)?),
+        };
+        backend.migrate().await?;
+        info!("Connected to the {:?} storage", config.kind);
+        let storage = Storage::new(backend);
+        if config.results_max_age_days > 0 {
+            storage.spawn_eviction(Duration::from_secs(config.results_max_age_days * 24 * 60 * 60));
+        }
+        Ok(Some(storage))
+    }
+
+    fn new(backend: Arc<dyn StorageBackend>) -> Self {
+        let users = ByteLru::new(KNOWN_USERS * size_of::<UserPreferences>());
+        Storage { backend, users: Arc::new(Mutex::new(users)) }
+    }
+
+    /// Deletes images and results unused for `max_age` right away and then every `EVICTION_INTERVAL`
+    fn spawn_eviction(&self, max_age: Duration) {
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                match storage.images().evict(max_age).await {
+                    Ok(0) => {},
+                    Ok(deleted) => info!("Deleted {} old images from the storage", deleted),
+                    Err(e) => warn!("Failed to delete old images from the storage: {}", e),
+                }
+                match storage.results().evict(max_age).await {
+                    Ok(0) => {},
+                    Ok(deleted) => info!("Deleted {} old results from the storage", deleted),
+                    Err(e) => warn!("Failed to delete old results from the storage: {}", e),
+                }
+            }
+        });
+    }
+
+    pub fn images(&self) -> ImageRepository<'_> {
+        ImageRepository { backend: self.backend.as_ref() }
+    }
+
+    pub fn results(&self) -> ResultRepository<'_> {
+        ResultRepository { backend: self.backend.as_ref() }
+    }
+
+    pub fn guilds(&self) -> GuildSettingsRepository<'_> {
+        GuildSettingsRepository { backend: self.backend.as_ref() }
+    }
+
+    pub fn users(&self) -> UserPreferencesRepository<'_> {
+        UserPreferencesRepository { backend: self.backend.as_ref(), known: &self.users }
+    }
+}
+
+/// Source files of attachments by the SHA-256 of their bytes, see [`calculate_sha256`]
+pub struct ImageRepository<'a> {
+    backend: &'a dyn StorageBackend,
+}
+
+impl ImageRepository<'_> {
+    /// Stores the file of the attachment, `hash` is its [`calculate_sha256`]
+    pub async fn store(&self, attachment_id: u64, hash: &str, bytes: &[u8]) -> Result<()> {
+        self.backend.put_blob(BlobTable::Images, hash, bytes).await?;
+        self.backend.put_attachment(attachment_id, hash).await
+    }
+
+    /// The hash and the file of an attachment which was stored before
+    pub async fn load(&self, attachment_id: u64) -> Result<Option<(String, Bytes)>> {
+        let image = self.backend.get_attachment(attachment_id).await?;
+        Ok(image.map(|(hash, bytes)| (hash, Bytes::from(bytes))))
+    }
+
+    /// Deletes the images which weren't used for `max_age`, together with their attachments
+    pub async fn evict(&self, max_age: Duration) -> Result<u64> {
+        self.backend.delete_blobs_older_than(BlobTable::Images, max_age).await
+    }
+}
+
+/// Encoded results of the result cache
+pub struct ResultRepository<'a> {
+    backend: &'a dyn StorageBackend,
+}
+
+impl ResultRepository<'_> {
+    pub async fn get(&self, key: &ResultKey) -> Result<Option<Vec<u8>>> {
+        self.backend.get_blob(BlobTable::Results, key.as_str()).await
+    }
+
+    pub async fn put(&self, key: &ResultKey, bytes: &[u8]) -> Result<()> {
+        self.backend.put_blob(BlobTable::Results, key.as_str(), bytes).await
+    }
+
+    /// Deletes the results which weren't used for `max_age`
+    pub async fn evict(&self, max_age: Duration) -> Result<u64> {
+        self.backend.delete_blobs_older_than(BlobTable::Results, max_age).await
+    }
+}
+
+pub struct GuildSettingsRepository<'a> {
+    backend: &'a dyn StorageBackend,
+}
+
+impl GuildSettingsRepository<'_> {
+    /// The settings of the guild, or the defaults if it has none
+    pub async fn get(&self, guild_id: u64) -> Result<GuildSettings> {
+        Ok(self.backend.get_guild_settings(guild_id).await?.unwrap_or_else(|| GuildSettings::new(guild_id)))
+    }
+
+    pub async fn save(&self, settings: &GuildSettings) -> Result<()> {
+        self.backend.put_guild_settings(settings).await
+    }
+}
+
+/// Remembers the preferences it read or saved for the last `KNOWN_USERS` users, so that saving them
+/// unchanged on every click doesn't write to the database
+pub struct UserPreferencesRepository<'a> {
+    backend: &'a dyn StorageBackend,
+    known: &'a Mutex<ByteLru<u64, UserPreferences>>,
+}
+
+impl UserPreferencesRepository<'_> {
+    pub async fn get(&self, user_id: u64) -> Result<Option<UserPreferences>> {
+        if let Some(preferences) = self.known.lock().unwrap().get(&user_id) {
+            return Ok(Some(preferences.clone()));
+        }
+        let preferences = self.backend.get_user_preferences(user_id).await?;
+        if let Some(preferences) = &preferences {
+            self.remember(preferences);
+        }
+        Ok(preferences)
+    }
+
+    /// Writes the preferences unless they are the ones which were read or saved last
+    pub async fn save(&self, preferences: &UserPreferences) -> Result<()> {
+        if self.known.lock().unwrap().get(&preferences.user_id).is_some_and(|known| known == preferences) {
+            return Ok(());
+        }
+        self.backend.put_user_preferences(preferences).await?;
+        self.remember(preferences);
+        Ok(())
+    }
+
+    fn remember(&self, preferences: &UserPreferences) {
+        self.known.lock().unwrap().insert(preferences.user_id, preferences.clone(), size_of::<UserPreferences>());
+    }
+}
+
+/// Hex encoded SHA-256 of the bytes, which is the key of images and results
+pub fn calculate_sha256(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Output format as stored in the database, NULL is the config default
+fn format_to_id(format: Option<OutputFormat>) -> Option<i16> {
+    format.map(|format| format as i16)
+}
+
+fn format_from_id(id: Option<i16>) -> Option<OutputFormat> {
+    id.and_then(|id| u8::try_from(id).ok()).and_then(OutputFormat::from_u8)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::utils::colors::NordOptions;
+    use crate::utils::palette::Palette;
+
+    /// Stores something in every repository and reads it back. `run` keeps the rows of
+    /// earlier runs apart, since a database server keeps them.
+    pub(crate) async fn repositories_round_trip(storage: &Storage, run: u64) {
+        let attachment_id = run;
+        let source = Bytes::from(format!("image {}", run));
+        assert_eq!(storage.images().load(attachment_id).await.unwrap(), None);
+        let hash = calculate_sha256(&source);
+        storage.images().store(attachment_id, &hash, &source).await.unwrap();
+        assert_eq!(storage.images().load(attachment_id).await.unwrap(), Some((hash.clone(), source)));
+
+        let key = ResultKey::new(&run.to_string(), &hash, &NordOptions::default(), &Palette::by_id(0), OutputFormat::Png, 90);
+        assert_eq!(storage.results().get(&key).await.unwrap(), None);
+        storage.results().put(&key, b"result").await.unwrap();
+        assert_eq!(storage.results().get(&key).await.unwrap(), Some(b"result".to_vec()));
+
+        assert_eq!(storage.guilds().get(run).await.unwrap(), GuildSettings::new(run));
+        let settings = GuildSettings { guild_id: run, brightness_threshold: Some(0.25), ask_to_darken: false };
+        storage.guilds().save(&settings).await.unwrap();
+        assert_eq!(storage.guilds().get(run).await.unwrap(), settings);
+
+        assert_eq!(storage.users().get(run).await.unwrap(), None);
+        let preferences = UserPreferences { user_id: run, palette: 2, output_format: Some(OutputFormat::WebpLossy), quality: Some(70) };
+        storage.users().save(&preferences).await.unwrap();
+        // a fresh storage doesn't know the preferences yet, so they are read from the database
+        let reconnected = Storage::new(storage.backend.clone());
+        assert_eq!(reconnected.users().get(run).await.unwrap(), Some(preferences));
+    }
+
+    #[tokio::test]
+    async fn sqlite_repositories_round_trip() {
+        let backend = sqlite::SqliteBackend::open(":memory:").unwrap();
+        backend.migrate().await.unwrap();
+        repositories_round_trip(&Storage::new(Arc::new(backend)), 1).await;
+    }
+
+    #[tokio::test]
+    async fn unchanged_preferences_are_not_written_again() {
+        let backend = sqlite::SqliteBackend::open(":memory:").unwrap();
+        backend.migrate().await.unwrap();
+        let backend: Arc<dyn StorageBackend> = Arc::new(backend);
+        let storage = Storage::new(backend.clone());
+        let preferences = UserPreferences { user_id: 1, palette: 2, output_format: None, quality: None };
+        storage.users().save(&preferences).await.unwrap();
+        // changed behind the back of the storage, which still knows the preferences it saved
+        backend.put_user_preferences(&UserPreferences { palette: 3, ..preferences.clone() }).await.unwrap();
+        storage.users().save(&preferences).await.unwrap();
+        assert_eq!(backend.get_user_preferences(1).await.unwrap().unwrap().palette, 3);
+        storage.users().save(&UserPreferences { palette: 4, ..preferences }).await.unwrap();
+        assert_eq!(backend.get_user_preferences(1).await.unwrap().unwrap().palette, 4);
+    }
+}
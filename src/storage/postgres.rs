@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use deadpool_postgres::{Config, Pool, PoolConfig, Runtime};
+use log::info;
+use tokio_postgres::NoTls;
+
+use super::{format_from_id, format_to_id, BlobTable, GuildSettings, StorageBackend, UserPreferences, MIGRATIONS};
+
+/// A Postgres server, reached through a pool of connections
+pub struct PostgresBackend {
+    pool: Pool,
+}
+
+impl PostgresBackend {
+    /// Creates the pool. Connections are opened on first use, so this doesn't fail for an unreachable server.
+    pub fn connect(url: &str, pool_size: usize) -> Result<Self> {
+        let config = Config {
+            url: Some(url.to_owned()),
+            pool: Some(PoolConfig::new(pool_size.max(1))),
+            ..Config::default()
+        };
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .map_err(|e| anyhow!("Failed to create the Postgres pool: {}", e))?;
+        Ok(PostgresBackend { pool })
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Object> {
+        self.pool.get().await.map_err(|e| anyhow!("Failed to connect to Postgres: {}", e))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PostgresBackend {
+    async fn migrate(&self) -> Result<()> {
+        let mut client = self.client().await?;
+        client
+            .batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY, applied_at TIMESTAMPTZ NOT NULL DEFAULT now())")
+            .await?;
+        for &(version, script, _) in MIGRATIONS {
+            let transaction = client.transaction().await?;
+            // the lock keeps two bots from migrating at the same time
+            transaction.batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE").await?;
+            let applied = transaction
+                .query_opt("SELECT version FROM schema_migrations WHERE version = $1", &[&version])
+                .await?
+                .is_some();
+            if !applied {
+                info!("Applying migration {}", version);
+                transaction.batch_execute(script).await?;
+                transaction.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[&version]).await?;
+            }
+            transaction.commit().await?;
+        }
+        Ok(())
+    }
+
+    async fn get_blob(&self, table: BlobTable, hash: &str) -> Result<Option<Vec<u8>>> {
+        let query = format!("UPDATE {} SET created_at = now() WHERE hash = $1 RETURNING data", table.name());
+        let row = self.client().await?.query_opt(&query, &[&hash]).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn put_blob(&self, table: BlobTable, hash: &str, data: &[u8]) -> Result<()> {
+        let query = format!("INSERT INTO {} (hash, data) VALUES ($1, $2) ON CONFLICT (hash) DO UPDATE SET created_at = now()", table.name());
+        self.client().await?.execute(&query, &[&hash, &data]).await?;
+        Ok(())
+    }
+
+    async fn delete_blobs_older_than(&self, table: BlobTable, max_age: Duration) -> Result<u64> {
+        let query = format!("DELETE FROM {} WHERE created_at < now() - make_interval(secs => $1)", table.name());
+        Ok(self.client().await?.execute(&query, &[&max_age.as_secs_f64()]).await?)
+    }
+
+    async fn get_attachment(&self, attachment_id: u64) -> Result<Option<(String, Vec<u8>)>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                "UPDATE images SET created_at = now() FROM attachments
+                 WHERE images.hash = attachments.hash AND attachments.attachment_id = $1
+                 RETURNING images.hash, images.data",
+                &[&(attachment_id as i64)],
+            )
+            .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    async fn put_attachment(&self, attachment_id: u64, hash: &str) -> Result<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO attachments (attachment_id, hash) VALUES ($1, $2)
+                 ON CONFLICT (attachment_id) DO UPDATE SET hash = $2",
+                &[&(attachment_id as i64), &hash],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_guild_settings(&self, guild_id: u64) -> Result<Option<GuildSettings>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                "SELECT brightness_threshold, ask_to_darken FROM guild_settings WHERE guild_id = $1",
+                &[&(guild_id as i64)],
+            )
+            .await?;
+        Ok(row.map(|row| GuildSettings {
+            guild_id,
+            brightness_threshold: row.get(0),
+            ask_to_darken: row.get(1),
+        }))
+    }
+
+    async fn put_guild_settings(&self, settings: &GuildSettings) -> Result<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO guild_settings (guild_id, brightness_threshold, ask_to_darken) VALUES ($1, $2, $3)
+                 ON CONFLICT (guild_id) DO UPDATE SET brightness_threshold = $2, ask_to_darken = $3",
+                &[&(settings.guild_id as i64), &settings.brightness_threshold, &settings.ask_to_darken],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_preferences(&self, user_id: u64) -> Result<Option<UserPreferences>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                "SELECT palette, output_format, quality FROM user_preferences WHERE user_id = $1",
+                &[&(user_id as i64)],
+            )
+            .await?;
+        Ok(row.map(|row| UserPreferences {
+            user_id,
+            palette: row.get::<_, i32>(0) as u16,
+            output_format: format_from_id(row.get(1)),
+            quality: row.get::<_, Option<i16>>(2).map(|quality| quality as u8),
+        }))
+    }
+
+    async fn put_user_preferences(&self, preferences: &UserPreferences) -> Result<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO user_preferences (user_id, palette, output_format, quality) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (user_id) DO UPDATE SET palette = $2, output_format = $3, quality = $4",
+                &[
+                    &(preferences.user_id as i64),
+                    &(preferences.palette as i32),
+                    &format_to_id(preferences.output_format),
+                    &preferences.quality.map(|quality| quality as i16),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::storage::tests::repositories_round_trip;
+    use crate::storage::Storage;
+
+    /// Run with `cargo test -- --ignored` and a Postgres server at `DATABASE_URL`
+    #[tokio::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn postgres_repositories_round_trip() {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let backend = PostgresBackend::connect(&url, 2).unwrap();
+        backend.migrate().await.unwrap();
+        // migrating again finds every migration applied
+        backend.migrate().await.unwrap();
+        let run = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64 % i64::MAX as u64;
+        repositories_round_trip(&Storage::new(Arc::new(backend)), run).await;
+    }
+}
@@ -0,0 +1,289 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{format_from_id, format_to_id, BlobTable, GuildSettings, StorageBackend, UserPreferences, MIGRATIONS};
+
+/// A SQLite file, or an in-memory database for `:memory:`.
+/// SQLite writes one transaction at a time anyway, so a single connection is shared
+/// and every query runs on a blocking thread.
+pub struct SqliteBackend {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &str) -> Result<Self> {
+        let connection = Connection::open(path).map_err(|e| anyhow!("Failed to open the SQLite database {}: {}", path, e))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        // evicting an image deletes its attachments through the foreign key
+        connection.pragma_update(None, "foreign_keys", true)?;
+        Ok(SqliteBackend { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    /// Runs `query` with the connection on a blocking thread
+    async fn run<T: Send + 'static>(&self, query: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static) -> Result<T> {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap())).await??;
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteBackend {
+    async fn migrate(&self) -> Result<()> {
+        self.run(|connection| {
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY, applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+            )?;
+            for &(version, _, script) in MIGRATIONS {
+                let transaction = connection.transaction()?;
+                let applied = transaction
+                    .query_row("SELECT version FROM schema_migrations WHERE version = ?1", [version], |_| Ok(()))
+                    .optional()?
+                    .is_some();
+                if !applied {
+                    info!("Applying migration {}", version);
+                    transaction.execute_batch(script)?;
+                    transaction.execute("INSERT INTO schema_migrations (version) VALUES (?1)", [version])?;
+                }
+                transaction.commit()?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_blob(&self, table: BlobTable, hash: &str) -> Result<Option<Vec<u8>>> {
+        let query = format!("UPDATE {} SET created_at = CURRENT_TIMESTAMP WHERE hash = ?1 RETURNING data", table.name());
+        let hash = hash.to_owned();
+        self.run(move |connection| connection.query_row(&query, [hash], |row| row.get(0)).optional()).await
+    }
+
+    async fn put_blob(&self, table: BlobTable, hash: &str, data: &[u8]) -> Result<()> {
+        let query = format!(
+            "INSERT INTO {} (hash, data) VALUES (?1, ?2) ON CONFLICT (hash) DO UPDATE SET created_at = CURRENT_TIMESTAMP",
+            table.name()
+        );
+        let (hash, data) = (hash.to_owned(), data.to_vec());
+        self.run(move |connection| connection.execute(&query, params![hash, data])).await?;
+        Ok(())
+    }
+
+    async fn delete_blobs_older_than(&self, table: BlobTable, max_age: Duration) -> Result<u64> {
+        let query = format!("DELETE FROM {} WHERE created_at < datetime('now', ?1)", table.name());
+        let modifier = format!("-{} seconds", max_age.as_secs());
+        let deleted = self.run(move |connection| connection.execute(&query, [modifier])).await?;
+        Ok(deleted as u64)
+    }
+
+    async fn get_attachment(&self, attachment_id: u64) -> Result<Option<(String, Vec<u8>)>> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "UPDATE images SET created_at = CURRENT_TIMESTAMP FROM attachments
+                     WHERE images.hash = attachments.hash AND attachments.attachment_id = ?1
+                     RETURNING hash, data",
+                    [attachment_id as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn put_attachment(&self, attachment_id: u64, hash: &str) -> Result<()> {
+        let hash = hash.to_owned();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO attachments (attachment_id, hash) VALUES (?1, ?2)
+                 ON CONFLICT (attachment_id) DO UPDATE SET hash = ?2",
+                params![attachment_id as i64, hash],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_guild_settings(&self, guild_id: u64) -> Result<Option<GuildSettings>> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "SELECT brightness_threshold, ask_to_darken FROM guild_settings WHERE guild_id = ?1",
+                    [guild_id as i64],
+                    |row| Ok(GuildSettings { guild_id, brightness_threshold: row.get(0)?, ask_to_darken: row.get(1)? }),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn put_guild_settings(&self, settings: &GuildSettings) -> Result<()> {
+        let settings = settings.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO guild_settings (guild_id, brightness_threshold, ask_to_darken) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (guild_id) DO UPDATE SET brightness_threshold = ?2, ask_to_darken = ?3",
+                params![settings.guild_id as i64, settings.brightness_threshold, settings.ask_to_darken],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_user_preferences(&self, user_id: u64) -> Result<Option<UserPreferences>> {
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "SELECT palette, output_format, quality FROM user_preferences WHERE user_id = ?1",
+                    [user_id as i64],
+                    |row| Ok(UserPreferences {
+                        user_id,
+                        palette: row.get(0)?,
+                        output_format: format_from_id(row.get(1)?),
+                        quality: row.get(2)?,
+                    }),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn put_user_preferences(&self, preferences: &UserPreferences) -> Result<()> {
+        let preferences = preferences.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO user_preferences (user_id, palette, output_format, quality) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (user_id) DO UPDATE SET palette = ?2, output_format = ?3, quality = ?4",
+                params![preferences.user_id as i64, preferences.palette, format_to_id(preferences.output_format), preferences.quality],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::image_processing::OutputFormat;
+
+    async fn migrated() -> SqliteBackend {
+        let backend = SqliteBackend::open(":memory:").unwrap();
+        backend.migrate().await.unwrap();
+        backend
+    }
+
+    #[tokio::test]
+    async fn migrating_again_changes_nothing() {
+        let backend = migrated().await;
+        backend.put_blob(BlobTable::Results, "a", b"result").await.unwrap();
+        backend.migrate().await.unwrap();
+        let applied: i64 = backend
+            .run(|connection| connection.query_row("SELECT COUNT(*) FROM schema_migrations", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+        assert_eq!(backend.get_blob(BlobTable::Results, "a").await.unwrap(), Some(b"result".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn blobs_keep_the_first_bytes_of_a_hash() {
+        let backend = migrated().await;
+        assert_eq!(backend.get_blob(BlobTable::Results, "a").await.unwrap(), None);
+        backend.put_blob(BlobTable::Results, "a", b"first").await.unwrap();
+        backend.put_blob(BlobTable::Results, "a", b"second").await.unwrap();
+        assert_eq!(backend.get_blob(BlobTable::Results, "a").await.unwrap(), Some(b"first".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn old_blobs_are_deleted() {
+        let backend = migrated().await;
+        backend.put_blob(BlobTable::Results, "old", b"old").await.unwrap();
+        backend.put_blob(BlobTable::Results, "new", b"new").await.unwrap();
+        backend
+            .run(|connection| connection.execute("UPDATE results SET created_at = datetime('now', '-2 days') WHERE hash = 'old'", []))
+            .await
+            .unwrap();
+        let deleted = backend.delete_blobs_older_than(BlobTable::Results, Duration::from_secs(24 * 60 * 60)).await.unwrap();
+        assert_eq!(deleted, 1);
+        assert_eq!(backend.get_blob(BlobTable::Results, "old").await.unwrap(), None);
+        assert_eq!(backend.get_blob(BlobTable::Results, "new").await.unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn used_blobs_are_not_deleted() {
+        let backend = migrated().await;
+        for hash in ["read", "stored again", "unused"] {
+            backend.put_blob(BlobTable::Results, hash, b"result").await.unwrap();
+        }
+        backend.put_blob(BlobTable::Images, "image", b"image").await.unwrap();
+        backend.put_attachment(1, "image").await.unwrap();
+        backend
+            .run(|connection| {
+                connection.execute("UPDATE results SET created_at = datetime('now', '-2 days')", [])?;
+                connection.execute("UPDATE images SET created_at = datetime('now', '-2 days')", [])
+            })
+            .await
+            .unwrap();
+        backend.get_blob(BlobTable::Results, "read").await.unwrap();
+        backend.put_blob(BlobTable::Results, "stored again", b"result").await.unwrap();
+        backend.get_attachment(1).await.unwrap();
+        let max_age = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(backend.delete_blobs_older_than(BlobTable::Results, max_age).await.unwrap(), 1);
+        assert_eq!(backend.get_blob(BlobTable::Results, "unused").await.unwrap(), None);
+        assert_eq!(backend.delete_blobs_older_than(BlobTable::Images, max_age).await.unwrap(), 0);
+        assert_eq!(backend.get_attachment(1).await.unwrap(), Some(("image".to_owned(), b"image".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn evicting_an_image_forgets_its_attachments() {
+        let backend = migrated().await;
+        assert_eq!(backend.get_attachment(1).await.unwrap(), None);
+        backend.put_blob(BlobTable::Images, "a", b"image").await.unwrap();
+        backend.put_attachment(1, "a").await.unwrap();
+        backend.put_attachment(2, "a").await.unwrap();
+        assert_eq!(backend.get_attachment(2).await.unwrap(), Some(("a".to_owned(), b"image".to_vec())));
+        backend
+            .run(|connection| connection.execute("UPDATE images SET created_at = datetime('now', '-2 days')", []))
+            .await
+            .unwrap();
+        backend.delete_blobs_older_than(BlobTable::Images, Duration::from_secs(24 * 60 * 60)).await.unwrap();
+        assert_eq!(backend.get_attachment(1).await.unwrap(), None);
+        let attachments: i64 = backend
+            .run(|connection| connection.query_row("SELECT COUNT(*) FROM attachments", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(attachments, 0);
+    }
+
+    #[tokio::test]
+    async fn guild_settings_round_trip() {
+        let backend = migrated().await;
+        assert_eq!(backend.get_guild_settings(1).await.unwrap(), None);
+        let mut settings = GuildSettings { guild_id: 1, brightness_threshold: Some(0.5), ask_to_darken: false };
+        backend.put_guild_settings(&settings).await.unwrap();
+        assert_eq!(backend.get_guild_settings(1).await.unwrap(), Some(settings.clone()));
+        settings.brightness_threshold = None;
+        settings.ask_to_darken = true;
+        backend.put_guild_settings(&settings).await.unwrap();
+        assert_eq!(backend.get_guild_settings(1).await.unwrap(), Some(settings));
+    }
+
+    #[tokio::test]
+    async fn user_preferences_round_trip() {
+        let backend = migrated().await;
+        assert_eq!(backend.get_user_preferences(1).await.unwrap(), None);
+        let mut preferences = UserPreferences { user_id: 1, palette: 3, output_format: Some(OutputFormat::Png), quality: Some(80) };
+        backend.put_user_preferences(&preferences).await.unwrap();
+        assert_eq!(backend.get_user_preferences(1).await.unwrap(), Some(preferences.clone()));
+        preferences.output_format = None;
+        preferences.quality = None;
+        backend.put_user_preferences(&preferences).await.unwrap();
+        assert_eq!(backend.get_user_preferences(1).await.unwrap(), Some(preferences));
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use log::warn;
+use image::DynamicImage;
+use reqwest::{redirect, Client, Url};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::utils::id_store::IdStore;
+use crate::utils::image_processing::decode_image;
+
+/// Reserved id of the "Choose Image" button, which asks the user for a background image
+pub const BACKGROUND_IMAGE_REQUEST: u16 = u16::MAX;
+/// Uploaded backgrounds which are kept before the oldest ones are dropped
+const BACKGROUND_CAPACITY: usize = 50;
+/// Backgrounds are scaled down to this size, they are blended behind the subject anyway
+const BACKGROUND_MAX_SIZE: u32 = 2048;
+/// Biggest download which is accepted as a background
+const BACKGROUND_MAX_BYTES: usize = 20 * 1024 * 1024;
+/// Hosts of Discord attachments. Nothing else is downloaded, so that users can't make the bot
+/// request internal services.
+const ATTACHMENT_HOSTS: [&str; 2] = ["cdn.discordapp.com", "media.discordapp.net"];
+
+/// Keeps the background images users uploaded, so that buttons can refer to them by id.
+/// Id 0 means no image.
+pub struct BackgroundStore {
+    images: RwLock<IdStore<Arc<DynamicImage>>>,
+}
+
+impl Default for BackgroundStore {
+    fn default() -> Self {
+        BackgroundStore { images: RwLock::new(IdStore::new(1..BACKGROUND_IMAGE_REQUEST, BACKGROUND_CAPACITY)) }
+    }
+}
+
+impl BackgroundStore {
+    /// Stores the image and returns its id
+    pub async fn insert(&self, image: DynamicImage) -> u16 {
+        let image = if image.width() > BACKGROUND_MAX_SIZE || image.height() > BACKGROUND_MAX_SIZE {
+            image.thumbnail(BACKGROUND_MAX_SIZE, BACKGROUND_MAX_SIZE)
+        } else {
+            image
+        };
+        self.images.write().await.insert(Arc::new(image))
+    }
+
+    /// Returns the image with the given id, if it's still there
+    pub async fn get(&self, id: u16) -> Option<Arc<DynamicImage>> {
+        if id == 0 {
+            return None;
+        }
+        let image = self.images.read().await.get(id).cloned();
+        if image.is_none() {
+            warn!("Background image {} is gone, falling back to the color", id);
+        }
+        image
+    }
+
+    /// Downloads and decodes the Discord attachment behind `url` and stores it
+    pub async fn download(&self, url: &str) -> Result<u16> {
+        let url = attachment_url(url)?;
+        // a redirect could lead away from Discord
+        let client = Client::builder().redirect(redirect::Policy::none()).build()?;
+        let mut response = client.get(url).send().await?;
+        if !response.status().is_success() {
+            bail!("Request failed with status code: {}", response.status());
+        }
+        let too_big = || anyhow::anyhow!("The image is bigger than {} MB", BACKGROUND_MAX_BYTES / 1024 / 1024);
+        if response.content_length().is_some_and(|length| length > BACKGROUND_MAX_BYTES as u64) {
+            return Err(too_big());
+        }
+        // the length can be missing or wrong, so the body is read in chunks up to the limit
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if bytes.len() + chunk.len() > BACKGROUND_MAX_BYTES {
+                return Err(too_big());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let image = tokio::task::spawn_blocking(move || decode_image(&bytes)).await??;
+        Ok(self.insert(image).await)
+    }
+}
+
+/// Parses the url and checks that it points to a Discord attachment
+fn attachment_url(url: &str) -> Result<Url> {
+    let Ok(url) = Url::parse(url) else {
+        bail!("`{}` is not a link", url);
+    };
+    let is_attachment = url.scheme() == "https"
+        && url.host_str().is_some_and(|host| ATTACHMENT_HOSTS.contains(&host))
+        && url.port().is_none()
+        && url.path().starts_with("/attachments/");
+    if !is_attachment {
+        bail!("Only links to images uploaded to Discord are supported");
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_discord_attachments_are_downloaded() {
+        assert!(attachment_url("https://cdn.discordapp.com/attachments/1/2/background.png?ex=1").is_ok());
+        assert!(attachment_url("https://media.discordapp.net/attachments/1/2/background.png").is_ok());
+        for url in [
+            "http://cdn.discordapp.com/attachments/1/2/background.png",
+            "https://cdn.discordapp.com:8080/attachments/1/2/background.png",
+            "https://cdn.discordapp.com.example.com/attachments/1/2/background.png",
+            "https://cdn.discordapp.com/avatars/1/2.png",
+            "https://127.0.0.1/attachments/1/2/background.png",
+            "http://169.254.169.254/latest/meta-data/",
+            "file:///etc/passwd",
+            "not a link",
+        ] {
+            assert!(attachment_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A least recently used map which is bounded by the bytes of its values instead of their count.
+/// It does no locking, the caches wrap it in the mutex which fits where they are used.
+pub struct ByteLru<K, V> {
+    /// value, its size and the generation it was used last
+    entries: HashMap<K, (V, usize, u64)>,
+    /// keys by the generation they were used last, the first one is the least recently used
+    order: BTreeMap<u64, K>,
+    bytes: usize,
+    max_bytes: usize,
+    generation: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> ByteLru<K, V> {
+    pub fn new(max_bytes: usize) -> Self {
+        ByteLru { entries: HashMap::new(), order: BTreeMap::new(), bytes: 0, max_bytes, generation: 0 }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (value, _, last_used) = self.entries.get_mut(key)?;
+        self.generation += 1;
+        let key = self.order.remove(last_used).unwrap();
+        self.order.insert(self.generation, key);
+        *last_used = self.generation;
+        Some(value)
+    }
+
+    /// Inserts a value which takes `size` bytes and returns how many values were evicted for it.
+    /// Values bigger than the whole budget are not stored.
+    pub fn insert(&mut self, key: K, value: V, size: usize) -> usize {
+        self.remove(&key);
+        if size > self.max_bytes {
+            return 0;
+        }
+        self.generation += 1;
+        self.order.insert(self.generation, key.clone());
+        self.entries.insert(key, (value, size, self.generation));
+        self.bytes += size;
+        let mut evicted = 0;
+        while self.bytes > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            let (_, size, _) = self.entries.remove(&oldest).unwrap();
+            self.bytes -= size;
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, size, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.bytes -= size;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of all values
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_inserted_values_are_evicted() {
+        let mut lru = ByteLru::new(10);
+        assert_eq!(lru.insert("a", 1, 4), 0);
+        assert_eq!(lru.insert("b", 2, 4), 0);
+        assert_eq!(lru.insert("c", 3, 4), 1);
+        assert_eq!(lru.get(&"a"), None);
+        assert_eq!(lru.get(&"b"), Some(&2));
+        assert_eq!((lru.len(), lru.bytes()), (2, 8));
+        assert_eq!(lru.insert("d", 4, 10), 2);
+        assert_eq!((lru.len(), lru.bytes()), (1, 10));
+    }
+
+    #[test]
+    fn get_marks_a_value_as_recently_used() {
+        let mut lru = ByteLru::new(10);
+        lru.insert("a", 1, 4);
+        lru.insert("b", 2, 4);
+        lru.get(&"a");
+        lru.insert("c", 3, 4);
+        assert_eq!(lru.get(&"a"), Some(&1));
+        assert_eq!(lru.get(&"b"), None);
+    }
+
+    #[test]
+    fn replacing_a_value_updates_the_bytes() {
+        let mut lru = ByteLru::new(10);
+        lru.insert("a", 1, 4);
+        lru.insert("a", 2, 6);
+        assert_eq!((lru.len(), lru.bytes()), (1, 6));
+        assert_eq!(lru.get(&"a"), Some(&2));
+    }
+
+    #[test]
+    fn values_bigger_than_the_budget_are_not_stored() {
+        let mut lru = ByteLru::new(10);
+        lru.insert("a", 1, 4);
+        assert_eq!(lru.insert("b", 2, 11), 0);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(&1));
+        // an oversized replacement drops the stale value
+        lru.insert("a", 3, 11);
+        assert!(lru.is_empty());
+        assert_eq!(lru.bytes(), 0);
+    }
+}
@@ -0,0 +1,290 @@
+// the matrices are copied verbatim from their references, even where f32 cannot hold every digit
+#![allow(clippy::excessive_precision)]
+
+use lazy_static::lazy_static;
+
+use crate::utils::colors::RgbColor;
+
+lazy_static! {
+    // the conversion is needed for every pixel, so it's done once for all 256 values
+    static ref SRGB_TO_LINEAR: [f32; 256] = std::array::from_fn(|value| srgb_to_linear(value as f32 / 255.0));
+}
+
+/// Converts an sRGB channel in [0, 1] to linear light
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear light channel back to sRGB in [0, 1]
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+fn linear_rgb(color: &RgbColor) -> (f32, f32, f32) {
+    (SRGB_TO_LINEAR[color.r as usize], SRGB_TO_LINEAR[color.g as usize], SRGB_TO_LINEAR[color.b as usize])
+}
+
+/// A color in the OKLab space (https://bottosson.github.io/posts/oklab/).
+/// `l` is in [0, 1], `a` and `b` roughly in [-0.4, 0.4].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Oklab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Oklab {
+    pub fn from_rgb(color: &RgbColor) -> Self {
+        let (r, g, b) = linear_rgb(color);
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+        Oklab {
+            l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        }
+    }
+
+    /// Linear sRGB values, which lie outside of [0, 1] for colors out of gamut
+    fn to_linear_rgb(self) -> [f32; 3] {
+        let l = (self.l + 0.3963377774 * self.a + 0.2158037573 * self.b).powi(3);
+        let m = (self.l - 0.1055613458 * self.a - 0.0638541728 * self.b).powi(3);
+        let s = (self.l - 0.0894841775 * self.a - 1.2914855480 * self.b).powi(3);
+        [
+            4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+            -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+            -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+        ]
+    }
+
+    /// Converts back to sRGB. Colors out of gamut lose chroma until they fit, which keeps their hue.
+    pub fn to_rgb(self) -> RgbColor {
+        let in_gamut = |rgb: &[f32; 3]| rgb.iter().all(|c| (-1e-4..=1. + 1e-4).contains(c));
+        let mut rgb = self.to_linear_rgb();
+        if !in_gamut(&rgb) {
+            // bisect the largest chroma scale which is still displayable
+            let (mut low, mut high) = (0f32, 1f32);
+            for _ in 0..12 {
+                let scale = (low + high) / 2.;
+                if in_gamut(&Oklab { a: self.a * scale, b: self.b * scale, ..self }.to_linear_rgb()) {
+                    low = scale;
+                } else {
+                    high = scale;
+                }
+            }
+            rgb = Oklab { a: self.a * low, b: self.b * low, ..self }.to_linear_rgb();
+        }
+        let channel = |value: f32| (linear_to_srgb(value.clamp(0., 1.)) * 255.).round() as u8;
+        RgbColor { r: channel(rgb[0]), g: channel(rgb[1]), b: channel(rgb[2]) }
+    }
+
+    /// Colorfulness, 0 for grays
+    pub fn chroma(&self) -> f32 {
+        self.a.hypot(self.b)
+    }
+
+    /// Turns the hue by `degrees` around the lightness axis, which keeps lightness and chroma
+    pub fn rotate_hue(&self, degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Oklab { l: self.l, a: self.a * cos - self.b * sin, b: self.a * sin + self.b * cos }
+    }
+
+    /// ΔE in OKLab, which is the euclidean distance
+    pub fn delta_e(&self, other: &Oklab) -> f32 {
+        ((self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+}
+
+/// A color in the CIELAB space with a D65 white point.
+/// `l` is in [0, 100], `a` and `b` roughly in [-128, 127].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+impl Lab {
+    pub fn from_rgb(color: &RgbColor) -> Self {
+        const WHITE: (f32, f32, f32) = (0.95047, 1.0, 1.08883);
+        let (r, g, b) = linear_rgb(color);
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / WHITE.0;
+        let y = (0.2126729 * r + 0.7151522 * g + 0.0721750 * b) / WHITE.1;
+        let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / WHITE.2;
+
+        let f = |t: f32| {
+            const DELTA: f32 = 6. / 29.;
+            if t > DELTA.powi(3) {
+                t.cbrt()
+            } else {
+                t / (3. * DELTA * DELTA) + 4. / 29.
+            }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        Lab {
+            l: 116. * fy - 16.,
+            a: 500. * (fx - fy),
+            b: 200. * (fy - fz),
+        }
+    }
+
+    /// ΔE*ab (CIE76)
+    pub fn delta_e(&self, other: &Lab) -> f32 {
+        ((self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+}
+
+/// How the difference between two colors is measured.
+/// The perceptual metrics are opt-in, so that the presets keep their look.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ColorMetric {
+    /// Difference in perceived brightness only
+    #[default]
+    Brightness,
+    /// Euclidean distance of the sRGB values
+    Rgb,
+    /// ΔE in OKLab
+    OkLab,
+    /// ΔE*ab in CIELAB
+    CieLab,
+}
+
+impl ColorMetric {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ColorMetric::Brightness),
+            1 => Some(ColorMetric::Rgb),
+            2 => Some(ColorMetric::OkLab),
+            3 => Some(ColorMetric::CieLab),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ColorMetric::Brightness => "Brightness",
+            ColorMetric::Rgb => "RGB",
+            ColorMetric::OkLab => "OKLab",
+            ColorMetric::CieLab => "CIELAB",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        ColorMetric::from_u8((*self as u8 + 1) % 4).unwrap()
+    }
+
+    pub fn is_perceptual(&self) -> bool {
+        matches!(self, ColorMetric::OkLab | ColorMetric::CieLab)
+    }
+
+    /// The metric colors are erased with. Brightness alone would erase every color of the same
+    /// brightness, so it erases by RGB distance, like before there were other metrics.
+    pub fn erase_metric(&self) -> ColorMetric {
+        match self {
+            ColorMetric::Brightness => ColorMetric::Rgb,
+            metric => *metric,
+        }
+    }
+
+    pub fn distance(&self, a: &RgbColor, b: &RgbColor) -> f32 {
+        match self {
+            ColorMetric::Brightness => (a.brightness() - b.brightness()).abs(),
+            ColorMetric::Rgb => a.color_distance(b),
+            ColorMetric::OkLab => Oklab::from_rgb(a).delta_e(&Oklab::from_rgb(b)),
+            ColorMetric::CieLab => Lab::from_rgb(a).delta_e(&Lab::from_rgb(b)),
+        }
+    }
+
+    /// Converts a distance given as euclidean sRGB distance (0-255 per channel)
+    /// into roughly the same perceived distance in this metric
+    pub fn scale_rgb_distance(&self, distance: f32) -> f32 {
+        match self {
+            ColorMetric::Brightness => distance / 255.,
+            ColorMetric::Rgb => distance,
+            ColorMetric::OkLab => distance / 400.,
+            ColorMetric::CieLab => distance / 4.,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: RgbColor = RgbColor { r: 255, g: 255, b: 255 };
+    const BLACK: RgbColor = RgbColor { r: 0, g: 0, b: 0 };
+    const RED: RgbColor = RgbColor { r: 255, g: 0, b: 0 };
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not {} ± {}", actual, expected, tolerance);
+    }
+
+    #[test]
+    fn oklab_of_known_colors() {
+        let white = Oklab::from_rgb(&WHITE);
+        assert_close(white.l, 1., 1e-3);
+        assert_close(white.chroma(), 0., 1e-3);
+        assert_eq!(Oklab::from_rgb(&BLACK), Oklab { l: 0., a: 0., b: 0. });
+        // reference values of https://bottosson.github.io/posts/oklab/
+        let red = Oklab::from_rgb(&RED);
+        assert_close(red.l, 0.628, 1e-3);
+        assert_close(red.a, 0.225, 1e-3);
+        assert_close(red.b, 0.126, 1e-3);
+    }
+
+    #[test]
+    fn cielab_of_known_colors() {
+        let white = Lab::from_rgb(&WHITE);
+        assert_close(white.l, 100., 0.01);
+        assert_close(white.a, 0., 0.01);
+        assert_close(white.b, 0., 0.01);
+        let black = Lab::from_rgb(&BLACK);
+        assert_close(black.l, 0., 0.01);
+        let red = Lab::from_rgb(&RED);
+        assert_close(red.l, 53.24, 0.01);
+        assert_close(red.a, 80.09, 0.01);
+        assert_close(red.b, 67.20, 0.01);
+        assert_close(red.delta_e(&white), 114.56, 0.05);
+    }
+
+    #[test]
+    fn oklab_round_trips_srgb() {
+        for color in [WHITE, BLACK, RED, RgbColor { r: 46, g: 52, b: 64 }, RgbColor { r: 136, g: 192, b: 208 }] {
+            assert_eq!(Oklab::from_rgb(&color).to_rgb(), color);
+        }
+    }
+
+    #[test]
+    fn hue_rotation_keeps_lightness_and_chroma() {
+        let red = Oklab::from_rgb(&RED);
+        let quarter = red.rotate_hue(90.);
+        assert_close(quarter.l, red.l, 1e-6);
+        assert_close(quarter.a, -red.b, 1e-6);
+        assert_close(quarter.b, red.a, 1e-6);
+        assert_close(red.rotate_hue(0.5).chroma(), red.chroma(), 1e-6);
+        assert!(red.rotate_hue(0.5).delta_e(&red) > 1e-3);
+        assert_eq!(red.rotate_hue(360.).to_rgb(), RED);
+    }
+
+    #[test]
+    fn metrics_measure_no_distance_between_equal_colors() {
+        let mut metric = ColorMetric::Brightness;
+        for _ in 0..4 {
+            assert_eq!(metric.distance(&RED, &RED), 0.);
+            assert!(metric.distance(&WHITE, &BLACK) > 0.);
+            metric = metric.next();
+        }
+        assert_eq!(metric, ColorMetric::Brightness);
+        assert_close(ColorMetric::OkLab.distance(&WHITE, &BLACK), 1., 1e-3);
+        assert_close(ColorMetric::CieLab.distance(&WHITE, &BLACK), 100., 0.01);
+    }
+}
@@ -1,17 +1,28 @@
-use image::imageops::overlay;
-use image::{DynamicImage, GenericImageView, RgbaImage, Rgb, Rgba};
-use imageproc::filter::gaussian_blur_f32;
-use onnxruntime::session::Session;
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage, Rgb, Rgba};
 use serenity::all::{ButtonStyle, CreateActionRow, CreateButton, ReactionType};
 use std::fmt::Display;
-use std::num::ParseIntError;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::vec;
-use onnxruntime::{environment::Environment, ndarray::Array4, tensor::OrtOwnedTensor, GraphOptimizationLevel};
-use ndarray;
+use rayon::prelude::*;
 use derivative::Derivative;
-
-use crate::config::load_config;
+use anyhow::{bail, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::color_space::{ColorMetric, Oklab};
+use crate::utils::custom_id::{CustomIdReader, CustomIdWriter};
+use crate::utils::image_processing::flood::{ALL_CORNERS, BOTTOM_LEFT, BOTTOM_RIGHT, TOP_LEFT, TOP_RIGHT};
+use crate::utils::image_processing::{
+    crop_mask, decontaminate_colors, dither, dominant_colors, flood_fill, fuse_masks, measure_border, par_pixels_mut, par_rows_mut, preprocess, refinement_radius, upsample_mask,
+    blur_backdrop, plan_tiles, BackgroundMode, ColorCache, DitherMode, DominantColor, EraseMode, FilterContext, FilterStep, MaskCleanup, MaskFusion, MaskRefinement, OutputFormat, Pipeline,
+    TileBlender,
+};
+use crate::utils::mask_cache::MaskKey;
+use crate::utils::model_manager::ModelManager;
+use crate::utils::models::{registry, BackgroundModel, ModelConfig, ModelPurpose};
+use crate::utils::backgrounds::BACKGROUND_IMAGE_REQUEST;
+use crate::utils::palette::{Palette, CUSTOM_PALETTE_START, PALETTE_REQUEST};
 
 #[derive(Clone, Debug)]
 pub enum ImageType {
@@ -19,70 +30,6 @@ pub enum ImageType {
     Picture
 }
 
-#[derive(Clone, Debug, Copy, PartialEq)]
-pub enum Models {
-    U2net,
-    IsnetAnime,
-    IsnetGeneral,
-    Algorithm,
-}
-
-#[derive(Clone, Debug)]
-pub struct Model {
-    pub id: usize,
-    pub path: String,
-    pub name: String,
-    pub width: u32,
-    pub height: u32,
-}
-
-impl Models {
-    pub fn to_struct(&self) -> Model {
-        let pwd: String = load_config().threshold.modelpath.into();
-        match self {
-            Models::U2net => Model {
-                id: 0,
-                path: String::from(format!("{pwd}/u2net.onnx")),
-                name: String::from("AI General 2"),
-                width: 320,
-                height: 320,
-            },
-            Models::IsnetAnime => Model {
-                id: 1,
-                path: String::from(format!("{pwd}/isnet-anime.onnx")),
-                name: String::from("AI Anime"),
-                width: 1024,
-                height: 1024,
-            },
-            Models::IsnetGeneral => Model {
-                id: 2,
-                path: String::from(format!("{pwd}/isnet-general-use.onnx")),
-                name: String::from("AI General"),
-                width: 1024,
-                height: 1024,
-            },
-            Models::Algorithm => Model {    
-                id: 3,
-                path: String::from("LOCAL"),
-                name: String::from("General"),
-                width: 320,
-                height: 320,
-            }
-        }
-    }
-
-    pub fn from_id(id: usize) -> Self {
-        match id {
-            0 => Models::U2net,
-            1 => Models::IsnetAnime,
-            2 => Models::IsnetGeneral,
-            3 => Models::Algorithm,
-            _ => Models::Algorithm,
-        }
-    }
-}
-
-
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub enum ActivationFunction {
     Linear,
@@ -90,6 +37,7 @@ pub enum ActivationFunction {
     ReLU,
     Tanh,
     Softmax,
+    Threshold,
 }
 impl ActivationFunction {
     pub fn from_u8(value: u8) -> Option<Self> {
@@ -99,6 +47,7 @@ impl ActivationFunction {
             2 => Some(ActivationFunction::ReLU),
             3 => Some(ActivationFunction::Tanh),
             4 => Some(ActivationFunction::Softmax),
+            5 => Some(ActivationFunction::Threshold),
             _ => None,
         }
     }
@@ -110,22 +59,52 @@ impl ActivationFunction {
             ActivationFunction::ReLU => "ReLU",
             ActivationFunction::Tanh => "Tanh",
             ActivationFunction::Softmax => "Softmax",
+            ActivationFunction::Threshold => "Threshold",
         }
     }
 
     pub fn next(&self) -> Self {
         let values = vec![
             ActivationFunction::Linear, ActivationFunction::Sigmoid, 
-            // ActivationFunction::ReLU, ActivationFunction::Tanh, 
-            // ActivationFunction::Softmax
+            ActivationFunction::ReLU, ActivationFunction::Tanh, 
+            ActivationFunction::Softmax, ActivationFunction::Threshold,
         ];
         let self_index = values.iter().position(|&x| x == *self).unwrap();
         let next = (self_index + 1) % (values.len());
         values[next]
     }
+
+    /// Maps a mask probability in [0, 1] to an alpha in [0, 1].
+    /// `center` is the probability where the curve cuts or turns, `steepness` how fast it rises there.
+    pub fn apply(&self, x: f32, center: f32, steepness: f32) -> f32 {
+        let logistic = |x: f32| 1. / (1. + (-steepness * (x - center)).exp());
+        let value = match self {
+            ActivationFunction::Linear => x,
+            // S-curve around the center, stretched so that 0 and 1 stay fixed
+            ActivationFunction::Sigmoid => (logistic(x) - logistic(0.)) / (logistic(1.) - logistic(0.)),
+            // cuts everything below the center and keeps the rest proportional
+            ActivationFunction::ReLU => (x - center) / (1. - center).max(f32::EPSILON),
+            // cuts everything below the center and saturates quickly above it
+            ActivationFunction::Tanh => (steepness * (x - center)).tanh() / (steepness * (1. - center)).tanh().max(f32::EPSILON),
+            // probability of foreground between the two classes foreground (x) and background (1 - x),
+            // with the steepness as inverse temperature and the center as decision boundary.
+            // Unlike the sigmoid, the ends stay soft for low steepness.
+            ActivationFunction::Softmax => {
+                let foreground = (steepness * x).exp();
+                foreground / (foreground + (steepness * (2. * center - x)).exp())
+            },
+            ActivationFunction::Threshold => if x >= center { 1. } else { 0. },
+        };
+        value.clamp(0., 1.)
+    }
+
+    /// The curve for every 8 bit mask value
+    pub fn lookup_table(&self, center: f32, steepness: f32) -> [u8; 256] {
+        std::array::from_fn(|value| (self.apply(value as f32 / 255., center, steepness) * 255.).round() as u8)
+    }
 }
 // implement clone
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 
 
 pub enum NordPreset {
@@ -133,26 +112,99 @@ pub enum NordPreset {
     Nord,
     StaticBackground,
     DynamicBackground,
+    SmartDark,
 }
 
 
 impl NordPreset {
     pub fn iter() -> Vec<NordPreset> {
-        vec![NordPreset::NordWithColor, NordPreset::Nord, NordPreset::StaticBackground, NordPreset::DynamicBackground]
+        vec![NordPreset::NordWithColor, NordPreset::Nord, NordPreset::StaticBackground, NordPreset::DynamicBackground, NordPreset::SmartDark]
+    }
+
+    /// Key of the preset in the `[presets]` table of `config.toml`
+    pub fn name(&self) -> &'static str {
+        match self {
+            NordPreset::NordWithColor => "nord_with_color",
+            NordPreset::Nord => "nord",
+            NordPreset::StaticBackground => "static_background",
+            NordPreset::DynamicBackground => "dynamic_background",
+            NordPreset::SmartDark => "smart_dark",
+        }
+    }
+
+    /// The steps of the preset. A pipeline with the same name in the `presets` of the config takes precedence.
+    pub fn pipeline(&self, presets: &HashMap<String, Vec<FilterStep>>) -> Pipeline {
+        if let Some(steps) = presets.get(self.name()) {
+            return Pipeline::from_steps(steps.clone());
+        }
+        let steps = match self {
+            NordPreset::NordWithColor => vec![
+                FilterStep::Invert, FilterStep::HueRotate { degrees: 180., perceptual: false }, FilterStep::Nord,
+            ],
+            NordPreset::Nord => vec![
+                FilterStep::Invert, FilterStep::Sepia, FilterStep::HueRotate { degrees: 180., perceptual: false }, FilterStep::Nord,
+            ],
+            NordPreset::StaticBackground | NordPreset::DynamicBackground => vec![
+                FilterStep::EraseBackground,
+            ],
+            NordPreset::SmartDark => vec![
+                FilterStep::SmartInvert { saturation_gate: Some(SMART_INVERT_SATURATION_GATE) },
+            ],
+        };
+        Pipeline::from_steps(steps)
+    }
+
+    /// The preset whose toggles equal the given options, if there is one
+    pub fn matching(options: &NordOptions) -> Option<NordPreset> {
+        NordPreset::iter().into_iter().find(|preset| options.is_preset(*preset))
     }
 }
 
+/// The pipeline for the options. Presets bring their own pipeline, everything else is built from the toggles.
+pub fn select_pipeline(options: &NordOptions, presets: &HashMap<String, Vec<FilterStep>>) -> Pipeline {
+    match NordPreset::matching(options) {
+        Some(preset) => preset.pipeline(presets),
+        None => Pipeline::from_options(options),
+    }
+}
 
+
+/// Which buttons are shown below the image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    /// presets only
+    Simple,
+    /// color filters, palettes and their strengths
+    Colors,
+    /// background removal and masks
+    Background,
+}
+
+impl Layout {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Layout::Simple),
+            1 => Some(Layout::Colors),
+            2 => Some(Layout::Background),
+            _ => None,
+        }
+    }
+}
+
+
+/// Everything the user picked for an image. Equal options give the same result, only the fields
+/// which steer the buttons and modals are ignored by `==`.
 #[derive(Clone, Debug, Derivative)]
 #[derivative(PartialEq)]
 pub struct NordOptions {
     pub invert: bool,
+    /// invert the lightness only, keeping hue and chroma
+    pub smart_invert: bool,
     pub hue_rotate: f32,
     pub sepia: bool,
     pub nord: bool,
     pub erase_most_present_color: bool,
 
-    #[derivative(PartialEq = "ignore")]
     pub erase_when_percentage: f64,
 
     #[derivative(PartialEq = "ignore")]
@@ -161,12 +213,87 @@ pub struct NordOptions {
     #[derivative(PartialEq = "ignore")]
     pub start: bool,
     
-    pub model: Models,
+    pub model: BackgroundModel,
     pub activation_function: ActivationFunction,
     pub background_color: Option<RgbColor>,
 
+    /// what the image is put on top of, if `background_color` is set
+    pub background_mode: BackgroundMode,
+
+    /// id of an uploaded image in the `BackgroundStore`, used by `BackgroundMode::Image`
+    pub background_image: u16,
+
+    /// id of a theme in `Palette::builtin` or of a palette in the `PaletteStore`
+    pub palette: u16,
+
+    /// how colors are compared when snapping to the palette and erasing the background
+    pub color_metric: ColorMetric,
+
+    /// how the error of snapping to the palette is spread
+    pub dither: DitherMode,
+
+    /// 0 keeps the image, 1 inverts it fully
+    pub invert_strength: f32,
+
+    /// 0 keeps the image, 1 applies the full sepia tone
+    pub sepia_strength: f32,
+
+    /// how far pixels are moved towards their nearest palette color
+    pub nord_strength: f32,
+
+    /// sRGB distance up to which colors count as the most present color when erasing it
+    pub erase_distance: f32,
+
+    /// how the mask of an AI model is scaled up and fitted to the edges of the image
+    pub mask_refinement: MaskRefinement,
+
+    /// mask probability where the activation function cuts or turns
+    pub mask_center: f32,
+
+    /// how fast the activation function rises around the center
+    pub mask_steepness: f32,
+
+    /// segment big images in overlapping tiles in addition to the whole image
+    pub high_detail: bool,
+
+    /// whether the dominant color is erased everywhere or only where it is connected to the border or corners
+    pub erase_mode: EraseMode,
+
+    /// corners which seed `EraseMode::Corners`, as bits of `image_processing::flood`
+    pub erase_corners: u8,
+
+    /// how many of the dominant colors are erased, starting with the most present one
+    pub erase_colors: u8,
+
+    /// how the hybrid eraser combines the model with the distance to the most present color
+    pub fusion: MaskFusion,
+
+    /// share of the model when the hybrid eraser blends
+    pub fusion_weight: f32,
+
+    /// removes islands, fills holes, grows or feathers the mask of both erasers
+    pub cleanup: MaskCleanup,
+
+    /// ask the user for the strengths above before applying the options
+    #[derivative(PartialEq = "ignore")]
+    pub tune: bool,
+
+    /// ask the user for the center and steepness of the mask before applying the options
+    #[derivative(PartialEq = "ignore")]
+    pub tune_mask: bool,
+
+    /// ask the user for the mask cleanup steps before applying the options
     #[derivative(PartialEq = "ignore")]
-    pub simple_layout: bool,
+    pub tune_cleanup: bool,
+
+    #[derivative(PartialEq = "ignore")]
+    pub layout: Layout,
+
+    /// overrides the configured output format
+    pub output_format: Option<OutputFormat>,
+
+    /// overrides the configured quality of lossy formats
+    pub quality: Option<u8>,
 }
 
 impl NordOptions {
@@ -178,6 +305,7 @@ impl NordOptions {
     pub fn default() -> Self {
         NordOptions {
             invert: true,
+            smart_invert: false,
             hue_rotate: 180.0,
             sepia: true,
             nord: true,
@@ -185,10 +313,34 @@ impl NordOptions {
             erase_when_percentage: 0.3,  // if met: all other filters are ignored
             auto_adjust: true,
             start: false,
-            model: Models::Algorithm,
+            model: BackgroundModel::Algorithm,
             activation_function: ActivationFunction::Sigmoid,
             background_color: None,
-            simple_layout: true,
+            background_mode: BackgroundMode::default(),
+            background_image: 0,
+            palette: 0,
+            color_metric: ColorMetric::default(),
+            dither: DitherMode::default(),
+            invert_strength: 1.0,
+            sepia_strength: 1.0,
+            nord_strength: 0.8,
+            erase_distance: 40.0,
+            mask_refinement: MaskRefinement::default(),
+            mask_center: 0.5,
+            mask_steepness: 10.0,
+            high_detail: false,
+            erase_mode: EraseMode::default(),
+            erase_corners: ALL_CORNERS,
+            erase_colors: 1,
+            fusion: MaskFusion::default(),
+            fusion_weight: 0.5,
+            cleanup: MaskCleanup::default(),
+            tune: false,
+            tune_mask: false,
+            tune_cleanup: false,
+            layout: Layout::Simple,
+            output_format: None,
+            quality: None,
         }
     }
 
@@ -208,7 +360,7 @@ impl NordOptions {
                 options.erase_when_percentage = 0.1;
                 options.auto_adjust = false;
                 options.start = false;
-                options.model = Models::Algorithm;
+                options.model = BackgroundModel::Algorithm;
                 options.background_color = None;
             },
             Some(ImageType::Picture) => {
@@ -222,7 +374,7 @@ impl NordOptions {
                 options.erase_when_percentage = 0.1;
                 options.auto_adjust = false;
                 options.start = false;
-                options.model = if is_probably_anime(image_information) { Models::IsnetAnime } else { Models::IsnetGeneral };
+                options.model = BackgroundModel::for_purpose(if is_probably_anime(image_information) { ModelPurpose::Anime } else { ModelPurpose::General });
                 options.background_color = None;
             } else {
                 // image without predominant color
@@ -234,7 +386,7 @@ impl NordOptions {
                 options.erase_when_percentage = 0.1;
                 options.auto_adjust = false;
                 options.start = false;
-                options.model = if is_probably_anime(image_information) { Models::IsnetAnime } else { Models::IsnetGeneral };
+                options.model = BackgroundModel::for_purpose(if is_probably_anime(image_information) { ModelPurpose::Anime } else { ModelPurpose::General });
                 options.background_color = None;
             }},
             None => {}
@@ -248,14 +400,20 @@ impl NordOptions {
                 NordOptions {
                     sepia: false,
                     auto_adjust: false, 
-                    simple_layout: nord_options.simple_layout,
+                    palette: nord_options.palette,
+                    layout: nord_options.layout,
+                    output_format: nord_options.output_format,
+                    quality: nord_options.quality,
                     ..NordOptions::default()
                 }
             },
             NordPreset::Nord => {
                 NordOptions { 
                     auto_adjust: false, 
-                    simple_layout: nord_options.simple_layout,
+                    palette: nord_options.palette,
+                    layout: nord_options.layout,
+                    output_format: nord_options.output_format,
+                    quality: nord_options.quality,
                     ..NordOptions::default()
                 }
             }
@@ -269,7 +427,7 @@ impl NordOptions {
                     erase_when_percentage: 0.1,
                     auto_adjust: false,
                     start: false,
-                    model: Models::Algorithm,
+                    model: BackgroundModel::Algorithm,
                     activation_function: ActivationFunction::Sigmoid,
                     background_color: None,
                     ..nord_options.clone()
@@ -285,77 +443,192 @@ impl NordOptions {
                     erase_when_percentage: 0.1,
                     auto_adjust: false,
                     start: false,
-                    model: Models::IsnetGeneral,
+                    model: BackgroundModel::for_purpose(ModelPurpose::General),
                     activation_function: ActivationFunction::Sigmoid,
                     background_color: None,
                     ..nord_options.clone()
                 }
+            },
+            NordPreset::SmartDark => {
+                NordOptions {
+                    invert: true,
+                    smart_invert: true,
+                    hue_rotate: 0.0,
+                    sepia: false,
+                    nord: false,
+                    auto_adjust: false,
+                    palette: nord_options.palette,
+                    layout: nord_options.layout,
+                    output_format: nord_options.output_format,
+                    quality: nord_options.quality,
+                    ..NordOptions::default()
+                }
             }
         }
     }
 
     pub fn is_any_preset(&self) -> bool {
-        for preset in NordPreset::iter() {
-            if self == &NordOptions::from_preset(preset, &NordOptions::default()) {
-                return true;
-            }
-        }
-        false
+        NordPreset::matching(self).is_some()
     }
 
+    /// Whether choosing the preset would keep these options, i.e. the options are the preset
+    /// with the palette and output settings the preset takes over
     pub fn is_preset(&self, preset: NordPreset) -> bool {
-        self == &NordOptions::from_preset(preset, &NordOptions::default())
+        self == &NordOptions::from_preset(preset, self)
     }
 
 
     pub fn make_nord_custom_id(&self, message_id: &u64, update: bool, id: Option<usize>) -> String {
         // id is needed to make the custom id unique since there could be buttons which do the same
-        format!(
-            "darken-{}-{}-{}-{}-{}-{}-{:.2}-{}-{}-{}-{}-{}-{}-{}-{}", 
-            update, self.invert, self.hue_rotate, 
-            self.sepia, self.nord, self.erase_most_present_color, 
-            self.erase_when_percentage, self.auto_adjust, 
-            self.start, self.model.to_struct().id, self.activation_function as u8,
-            id.unwrap_or(0), if self.background_color.is_some() {
-                                self.background_color.unwrap().as_hex()
-                            } else {"None".to_string()}, 
-            self.simple_layout, message_id,
-        )
+        let mut writer = CustomIdWriter::new();
+        writer
+            .flags(&[
+                update, self.invert, self.sepia, self.nord, self.erase_most_present_color,
+                self.auto_adjust, self.start, self.background_color.is_some(),
+                self.smart_invert, self.tune, self.tune_mask, self.high_detail,
+                self.tune_cleanup, self.model.is_hybrid(),
+            ])
+            .f32(self.hue_rotate)
+            .u8((self.erase_when_percentage * 100.).round() as u8)
+            .u8(self.model.id())
+            .u8(self.activation_function as u8)
+            .u8(id.unwrap_or(0) as u8)
+            .u16(self.palette)
+            .u8(self.color_metric as u8)
+            .u8(self.dither as u8)
+            .u8(self.layout as u8)
+            .u8((self.invert_strength * 100.).round() as u8)
+            .u8((self.sepia_strength * 100.).round() as u8)
+            .u8((self.nord_strength * 100.).round() as u8)
+            .u8(self.erase_distance.round() as u8)
+            .u8(self.mask_refinement as u8)
+            .u8((self.mask_center * 100.).round() as u8)
+            .u8((self.mask_steepness * 10.).round() as u8)
+            .u8((self.cleanup.min_island * 1000.).round() as u8)
+            .u8((self.cleanup.max_hole * 1000.).round() as u8)
+            .u8(self.cleanup.grow as u8)
+            .u8((self.cleanup.feather * 10.).round() as u8)
+            .u8(self.erase_mode as u8)
+            .u8(self.erase_corners)
+            .u8(self.erase_colors)
+            .u8(self.fusion as u8)
+            .u8((self.fusion_weight * 100.).round() as u8)
+            .u8(self.output_format.map_or(0, |format| format as u8 + 1))
+            .u8(self.quality.unwrap_or(0));
+        if let Some(color) = self.background_color {
+            writer.u8(color.r).u8(color.g).u8(color.b).u8(self.background_mode as u8).u16(self.background_image);
+        }
+        format!("darken-{}-{}", writer.encode(), message_id)
     }
     
-    pub fn from_custom_id(custom_id: &str) -> Self {
+    /// The options encoded like the custom id, without the ones which only steer the buttons,
+    /// so that options with equal results share one id
+    pub fn canonical_id(&self) -> String {
+        let canonical = NordOptions {
+            auto_adjust: false,
+            start: false,
+            tune: false,
+            tune_mask: false,
+            tune_cleanup: false,
+            layout: Layout::Simple,
+            ..self.clone()
+        };
+        canonical.make_nord_custom_id(&0, false, None)
+    }
+
+    pub fn from_custom_id(custom_id: &str) -> Result<Self> {
         let mut parts = custom_id.split("-").skip(1);
-        let _update = parts.next().unwrap().parse::<bool>().unwrap();
-        let invert = parts.next().unwrap().parse::<bool>().unwrap();
-        let hue_rotate = parts.next().unwrap().parse::<f32>().unwrap();
-        let sepia = parts.next().unwrap().parse::<bool>().unwrap();
-        let nord = parts.next().unwrap().parse::<bool>().unwrap();
-        let erase_most_present_color = parts.next().unwrap().parse::<bool>().unwrap();
-        let erase_when_percentage = parts.next().unwrap().parse::<f64>().unwrap();
-        let auto_adjust = parts.next().unwrap().parse::<bool>().unwrap();
-        let start = parts.next().unwrap().parse::<bool>().unwrap();
-        let model_id: usize = parts.next().unwrap().parse::<usize>().unwrap();
-        let model = Models::from_id(model_id);
-        let activation_function_id = parts.next().unwrap().parse::<u8>().unwrap();
-        let activation_function = ActivationFunction::from_u8(activation_function_id)
-            .expect(&format!("Invalid ActivationFunction ID: {}", activation_function_id));
-        let _id = parts.next().unwrap().parse::<usize>().unwrap();
-        // colors in format r;g;b
-        let background_color_str: &str = parts.next().unwrap();
-        let background_color = if background_color_str == "None" {
-            None
+        let Some(encoded) = parts.next() else {
+            bail!("Custom id without options: {}", custom_id);
+        };
+        let mut reader = CustomIdReader::decode(encoded)?;
+        let [
+            _update, invert, sepia, nord, erase_most_present_color,
+            auto_adjust, start, has_background_color,
+            smart_invert, tune, tune_mask, high_detail,
+            tune_cleanup, hybrid,
+        ] = reader.flags()?;
+        let hue_rotate = reader.f32()?;
+        let erase_when_percentage = reader.u8()? as f64 / 100.;
+        let model_id = reader.u8()?;
+        let model = if hybrid {
+            BackgroundModel::hybrid_from_id(model_id)?
         } else {
-            Some(RgbColor::from_hex(background_color_str).unwrap())
+            BackgroundModel::from_id(model_id)?
         };
-        let simple_layout = parts.next().unwrap().parse::<bool>().unwrap();
-        let _message_id = parts.next().unwrap().parse::<u64>().unwrap();
-        NordOptions {
-            invert, hue_rotate, sepia, 
+        let activation_function_id = reader.u8()?;
+        let Some(activation_function) = ActivationFunction::from_u8(activation_function_id) else {
+            bail!("Invalid ActivationFunction ID: {}", activation_function_id);
+        };
+        let _id = reader.u8()?;
+        let palette = reader.u16()?;
+        let color_metric_id = reader.u8()?;
+        let Some(color_metric) = ColorMetric::from_u8(color_metric_id) else {
+            bail!("Invalid ColorMetric ID: {}", color_metric_id);
+        };
+        let dither_id = reader.u8()?;
+        let Some(dither) = DitherMode::from_u8(dither_id) else {
+            bail!("Invalid DitherMode ID: {}", dither_id);
+        };
+        let layout_id = reader.u8()?;
+        let Some(layout) = Layout::from_u8(layout_id) else {
+            bail!("Invalid Layout ID: {}", layout_id);
+        };
+        let invert_strength = reader.u8()? as f32 / 100.;
+        let sepia_strength = reader.u8()? as f32 / 100.;
+        let nord_strength = reader.u8()? as f32 / 100.;
+        let erase_distance = reader.u8()? as f32;
+        let mask_refinement_id = reader.u8()?;
+        let Some(mask_refinement) = MaskRefinement::from_u8(mask_refinement_id) else {
+            bail!("Invalid MaskRefinement ID: {}", mask_refinement_id);
+        };
+        let mask_center = reader.u8()? as f32 / 100.;
+        let mask_steepness = reader.u8()? as f32 / 10.;
+        let cleanup = MaskCleanup {
+            min_island: reader.u8()? as f32 / 1000.,
+            max_hole: reader.u8()? as f32 / 1000.,
+            grow: reader.u8()? as i8,
+            feather: reader.u8()? as f32 / 10.,
+        };
+        let erase_mode_id = reader.u8()?;
+        let Some(erase_mode) = EraseMode::from_u8(erase_mode_id) else {
+            bail!("Invalid EraseMode ID: {}", erase_mode_id);
+        };
+        let erase_corners = reader.u8()? & ALL_CORNERS;
+        let erase_colors = reader.u8()?.clamp(1, MAX_ERASE_COLORS);
+        let fusion_id = reader.u8()?;
+        let Some(fusion) = MaskFusion::from_u8(fusion_id) else {
+            bail!("Invalid MaskFusion ID: {}", fusion_id);
+        };
+        let fusion_weight = reader.u8()? as f32 / 100.;
+        let output_format = match reader.u8()? {
+            0 => None,
+            format_id => match OutputFormat::from_u8(format_id - 1) {
+                Some(format) => Some(format),
+                None => bail!("Invalid OutputFormat ID: {}", format_id - 1),
+            },
+        };
+        let quality = Some(reader.u8()?).filter(|&quality| quality != 0);
+        let (background_color, background_mode, background_image) = if has_background_color {
+            let color = RgbColor { r: reader.u8()?, g: reader.u8()?, b: reader.u8()? };
+            let background_mode_id = reader.u8()?;
+            let Some(background_mode) = BackgroundMode::from_u8(background_mode_id) else {
+                bail!("Invalid BackgroundMode ID: {}", background_mode_id);
+            };
+            (Some(color), background_mode, reader.u16()?)
+        } else {
+            (None, BackgroundMode::default(), 0)
+        };
+        Ok(NordOptions {
+            invert, smart_invert, hue_rotate, sepia, 
             nord, erase_most_present_color, 
             erase_when_percentage, auto_adjust, 
-            start, model, activation_function, background_color,
-            simple_layout
-        }
+            start, model, activation_function, background_color, background_mode, background_image,
+            palette, color_metric, dither,
+            invert_strength, sepia_strength, nord_strength, erase_distance,
+            mask_refinement, mask_center, mask_steepness, high_detail, erase_mode, erase_corners, erase_colors, fusion, fusion_weight, cleanup,
+            tune, tune_mask, tune_cleanup, layout, output_format, quality
+        })
     }
 
     pub fn modal_get_color(&self) {
@@ -369,9 +642,10 @@ impl NordOptions {
 
         println!("make components with bg: {:?}", self.background_color);
         // make option lists, so that the clicked button is inverted
-        let option_2d_list = match self.simple_layout {
-            true => self._generate_simple_compoenents(),
-            false => self._generate_detailed_components(),
+        let option_2d_list = match self.layout {
+            Layout::Simple => self._generate_simple_compoenents(),
+            Layout::Colors => self._generate_color_components(),
+            Layout::Background => self._generate_background_components(),
         };
 
         let mut name_to_color_map = HashMap::<&str, ButtonStyle>::new();
@@ -430,6 +704,8 @@ impl NordOptions {
             );
         }
         components.push(CreateActionRow::Buttons(last_row));
+        // Discord rejects messages with more action rows
+        debug_assert!(components.len() <= 5, "{} action rows for {:?}", components.len(), self.layout);
 
         components
     }
@@ -437,12 +713,11 @@ impl NordOptions {
     fn _generate_simple_compoenents(&self) -> Vec<Vec<(String, bool, NordOptions, bool)>> {
         let mut self_no_start = self.clone();
         self_no_start.start = false;
-        let layout_label = if !self.simple_layout { "Simple Layout" } else { "Advanced Layout" };
 
         // make option lists, so that the clicked button is inverted
         let option_2d_list: Vec<Vec<(String, bool, NordOptions, bool)>> = vec![
             vec![
-                ("▼ More Options".into(), !self.simple_layout, NordOptions {simple_layout: !self.simple_layout, ..self_no_start}, true)
+                ("▼ More Options".into(), false, NordOptions {layout: Layout::Colors, ..self_no_start}, true)
             ],
             // preset vec
             vec![
@@ -450,65 +725,194 @@ impl NordOptions {
                 ("Mono Dark".into(), self.is_preset(NordPreset::Nord), NordOptions::from_preset(NordPreset::Nord, &self_no_start), true),
                 ("Static Background".into(), self.is_preset(NordPreset::StaticBackground), NordOptions::from_preset(NordPreset::StaticBackground, &self_no_start), true),
                 ("Dynamic Background".into(), self.is_preset(NordPreset::DynamicBackground), NordOptions::from_preset(NordPreset::DynamicBackground, &self_no_start), true),
+                ("Smart Dark".into(), self.is_preset(NordPreset::SmartDark), NordOptions::from_preset(NordPreset::SmartDark, &self_no_start), true),
             ],
         ];
         option_2d_list
     }
 
-    fn _generate_detailed_components(&self) -> Vec<Vec<(String, bool, NordOptions, bool)>> {
+    fn _generate_preset_row(&self) -> Vec<(String, bool, NordOptions, bool)> {
         let mut self_no_start = self.clone();
         self_no_start.start = false;
+        vec![
+            //("Presets:".into(), self.is_any_preset(), NordOptions { ..self_no_start}, false),
+            ("Nord w/ Color".into(), self.is_preset(NordPreset::NordWithColor), NordOptions::from_preset(NordPreset::NordWithColor, &self_no_start), true),
+            ("Nord w/o Color".into(), self.is_preset(NordPreset::Nord), NordOptions::from_preset(NordPreset::Nord, &self_no_start), true),
+            ("Static Background".into(), self.is_preset(NordPreset::StaticBackground), NordOptions::from_preset(NordPreset::StaticBackground, &self_no_start), true),
+            ("Dynamic Background".into(), self.is_preset(NordPreset::DynamicBackground), NordOptions::from_preset(NordPreset::DynamicBackground, &self_no_start), true),
+            ("Smart Dark".into(), self.is_preset(NordPreset::SmartDark), NordOptions::from_preset(NordPreset::SmartDark, &self_no_start), true),
+        ]
+    }
 
-        let is_model_enabled = |x: &Self| {
-            x.erase_most_present_color
+    fn _generate_color_components(&self) -> Vec<Vec<(String, bool, NordOptions, bool)>> {
+        let mut self_no_start = self.clone();
+        self_no_start.start = false;
+
+        let palette_name = format!("Palette: {}", Palette::name_of(self.palette));
+        let dither_name = format!("Dither: {}", self.dither.as_str());
+        let invert_name = if self.invert && self.smart_invert { "Smart Invert" } else { "Invert" };
+        let metric_name = format!("Colors by: {}", self.color_metric.as_str());
+        let format_name = format!("Format: {}", self.output_format.map_or("Default", |format| format.as_str()));
+        let quality_name = match self.quality {
+            Some(quality) => format!("Quality: {}", quality),
+            None => "Quality: Default".to_owned(),
         };
-        let background_color = if self.background_color.is_some() {self.background_color.unwrap().to_string()} else {"None".to_owned()};
-        let function_name = format!("Mask Function: {}", self.activation_function.as_str());
-        println!("make components with bg: {:?}", self.background_color);
         // make option lists, so that the clicked button is inverted
         let option_2d_list: Vec<Vec<(String, bool, NordOptions, bool)>> = vec![
             // component row
             vec![
                 // component
                 //name: intert, blue/gray, When click, then switch enabled/disabled, is enabled // arrow up str: ▲ // arrow down str: ▼
-                ("▲ Show only Presets".into(), !self.simple_layout, NordOptions {simple_layout: !self.simple_layout, ..self_no_start}, true),
-                ("Invert".into(), self.invert, NordOptions {invert: !self.invert, ..self_no_start}, true),
-                ("Hue Rotate".into(), if self.hue_rotate == 180. {true} else {false}, NordOptions {hue_rotate: if self.hue_rotate == 180. {0.} else {180.}, ..self_no_start}, true),
+                ("▲ Show only Presets".into(), false, NordOptions {layout: Layout::Simple, ..self_no_start}, true),
+                ("▶ Background".into(), false, NordOptions {layout: Layout::Background, ..self_no_start}, true),
+                // cycles off -> invert -> smart invert
+                (invert_name.into(), self.invert, NordOptions {invert: !self.invert || !self.smart_invert, smart_invert: self.invert && !self.smart_invert, ..self_no_start}, true),
+                ("Hue Rotate".into(), self.hue_rotate != 0., NordOptions {hue_rotate: if self.hue_rotate != 0. {0.} else {180.}, ..self_no_start}, true),
                 ("Sepia".into(), self.sepia, NordOptions {sepia: !self.sepia, ..self_no_start}, true),
-                ("Nord".into(), self.nord, NordOptions {nord: !self.nord, ..self_no_start}, true),
             ],
             vec![
-                ("Erase Background".into(), self.erase_most_present_color, NordOptions {erase_most_present_color: !self.erase_most_present_color, ..self_no_start}, true),
-                ("Dominant Color".into(), self.model == Models::Algorithm, NordOptions {model: Models::Algorithm, ..self_no_start}, is_model_enabled(self)),
-                ("General Use".into(), self.model == Models::IsnetGeneral, NordOptions {model: Models::IsnetGeneral, ..self_no_start}, is_model_enabled(self)),
-                //("General Use 2", self.model == Models::U2net, NordOptions {model: Models::U2net, ..self_no_start}, is_model_enabled(self)),
-                ("Anime".into(), self.model == Models::IsnetAnime, NordOptions {model: Models::IsnetAnime, ..self_no_start}, is_model_enabled(self)),
-                (function_name, true, NordOptions {activation_function: self.activation_function.next(), ..self_no_start}, is_model_enabled(self))
+                ("Nord".into(), self.nord, NordOptions {nord: !self.nord, ..self_no_start}, true),
+                (palette_name, true, NordOptions {palette: Palette::next_id(self.palette), ..self_no_start}, self.nord),
+                ("Custom Palette".into(), self.palette >= CUSTOM_PALETTE_START, NordOptions {palette: PALETTE_REQUEST, ..self_no_start}, self.nord),
+                (dither_name, self.dither != DitherMode::None, NordOptions {dither: self.dither.next(), ..self_no_start}, self.nord),
+                ("Tune Strengths".into(), false, NordOptions {tune: true, ..self_no_start}, true),
             ],
             vec![
-                ("Set Background".into(), self.background_color.is_some(), NordOptions {background_color: if self.background_color.is_some() {None} else {Some(RgbColor::from_hex("424242").unwrap())}, ..self_no_start}, true),
-                (background_color, self.background_color.is_some(), NordOptions {background_color: Some(RgbColor::from_hex("000001").unwrap()), ..self_no_start}, self.background_color.is_some()),  // 000001 is reserved for setting new color
+                (format_name, self.output_format.is_some(), NordOptions {output_format: next_output_format(self.output_format), ..self_no_start}, true),
+                (quality_name, self.quality.is_some(), NordOptions {quality: next_quality(self.quality), ..self_no_start}, self.output_format.is_none_or(|format| format.is_lossy())),
+                // the metric is used to snap to the palette and to erase the most present color
+                (metric_name, self.color_metric != ColorMetric::default(), NordOptions {color_metric: self.color_metric.next(), ..self_no_start}, self.nord || self.erase_most_present_color),
             ],
             // preset vec
+            self._generate_preset_row(),
+        ];
+        option_2d_list
+    }
+
+    fn _generate_background_components(&self) -> Vec<Vec<(String, bool, NordOptions, bool)>> {
+        let mut self_no_start = self.clone();
+        self_no_start.start = false;
+
+        let is_model_enabled = |x: &Self| {
+            x.erase_most_present_color
+        };
+        let background_color = if self.background_color.is_some() {self.background_color.unwrap().to_string()} else {"None".to_owned()};
+        let function_name = format!("Mask Function: {}", self.activation_function.as_str());
+        let refinement_name = format!("Edges: {}", self.mask_refinement.as_str());
+        // make option lists, so that the clicked button is inverted
+        let mut option_2d_list: Vec<Vec<(String, bool, NordOptions, bool)>> = vec![
             vec![
-                //("Presets:".into(), self.is_any_preset(), NordOptions { ..self_no_start}, false),
-                ("Nord w/ Color".into(), self.is_preset(NordPreset::NordWithColor), NordOptions::from_preset(NordPreset::NordWithColor, &self_no_start), true),
-                ("Nord w/o Color".into(), self.is_preset(NordPreset::Nord), NordOptions::from_preset(NordPreset::Nord, &self_no_start), true),
-                ("Static Background".into(), self.is_preset(NordPreset::StaticBackground), NordOptions::from_preset(NordPreset::StaticBackground, &self_no_start), true),
-                ("Dynamic Background".into(), self.is_preset(NordPreset::DynamicBackground), NordOptions::from_preset(NordPreset::DynamicBackground, &self_no_start), true),
-            ]
+                ("▲ Show only Presets".into(), false, NordOptions {layout: Layout::Simple, ..self_no_start.clone()}, true),
+                ("◀ Colors".into(), false, NordOptions {layout: Layout::Colors, ..self_no_start.clone()}, true),
+                ("Erase Background".into(), self.erase_most_present_color, NordOptions {erase_most_present_color: !self.erase_most_present_color, ..self_no_start.clone()}, true),
+                ("High Detail".into(), self.high_detail, NordOptions {high_detail: !self.high_detail, ..self_no_start.clone()}, is_model_enabled(self) && self.model != BackgroundModel::Algorithm),
+                ("Clean Mask".into(), self.cleanup.is_enabled(), NordOptions {tune_cleanup: true, ..self_no_start.clone()}, is_model_enabled(self)),
+            ],
+        ];
+        // one button per configured model and the hybrid eraser, in a single row
+        let mut models: Vec<BackgroundModel> = std::iter::once(BackgroundModel::Algorithm)
+            .chain(registry().models().iter().map(|model| BackgroundModel::Onnx(model.id)))
+            .take(MAX_MODEL_BUTTONS - 1)
+            .collect();
+        // a selected model which has no button of its own takes the place of the last one
+        if let BackgroundModel::Onnx(_) = self.model {
+            if !models.contains(&self.model) {
+                *models.last_mut().unwrap() = self.model;
+            }
+        }
+        // the hybrid eraser keeps the selected model or uses the general one
+        if let Some(id) = self.model.model_id().or(BackgroundModel::for_purpose(ModelPurpose::General).model_id()) {
+            models.push(BackgroundModel::Hybrid(id));
+        }
+        let model_button = |model: BackgroundModel| match model {
+            BackgroundModel::Hybrid(_) => (
+                format!("Hybrid: {}", self.fusion.as_str()),
+                self.model.is_hybrid(),
+                // clicking the selected hybrid eraser switches how the masks are fused
+                NordOptions {model, fusion: if self.model.is_hybrid() {self.fusion.next()} else {self.fusion}, ..self_no_start.clone()},
+                is_model_enabled(self),
+            ),
+            _ => (model.name(), self.model == model, NordOptions {model, ..self_no_start.clone()}, is_model_enabled(self)),
+        };
+        option_2d_list.push(models.into_iter().map(model_button).collect());
+        // cycles through no background and every background mode
+        let (background_name, next_background) = match (self.background_color, self.background_mode.next()) {
+            (None, _) => ("Set Background".to_owned(), NordOptions {background_color: Some(RgbColor::from_hex("424242").unwrap()), background_mode: BackgroundMode::Color, ..self_no_start.clone()}),
+            (Some(_), Some(mode)) => (format!("Background: {}", self.background_mode.as_str()), NordOptions {background_mode: mode, ..self_no_start.clone()}),
+            (Some(_), None) => (format!("Background: {}", self.background_mode.as_str()), NordOptions {background_color: None, background_mode: BackgroundMode::Color, background_image: 0, ..self_no_start.clone()}),
+        };
+        let background_source = if self.background_color.is_some() && self.background_mode == BackgroundMode::Image {
+            ("Choose Image".to_owned(), self.background_image != 0, NordOptions {background_image: BACKGROUND_IMAGE_REQUEST, ..self_no_start.clone()}, true)
+        } else {
+            // gradients, blur and pattern take their colors from the palette and the image
+            (background_color, self.background_color.is_some(), NordOptions {background_color: Some(RgbColor::from_hex("000001").unwrap()), ..self_no_start.clone()}, self.background_color.is_some() && self.background_mode == BackgroundMode::Color)  // 000001 is reserved for setting new color
+        };
+        let mut background_row = vec![
+            (background_name, self.background_color.is_some(), next_background, true),
+            background_source,
         ];
+        if self.model == BackgroundModel::Algorithm {
+            // the mask settings only apply to models, the fill only to the dominant color
+            let fill_name = format!("Erase: {}", self.erase_mode.as_str());
+            background_row.push((fill_name, self.erase_mode != EraseMode::Global, NordOptions {erase_mode: self.erase_mode.next(), ..self_no_start.clone()}, is_model_enabled(self)));
+            let colors_name = format!("Colors: {}", self.erase_colors);
+            background_row.push((colors_name, self.erase_colors > 1, NordOptions {erase_colors: self.erase_colors % MAX_ERASE_COLORS + 1, ..self_no_start.clone()}, is_model_enabled(self)));
+            option_2d_list.push(background_row);
+            if self.erase_mode == EraseMode::Corners {
+                option_2d_list.push([("◤ Top Left", TOP_LEFT), ("◥ Top Right", TOP_RIGHT), ("◣ Bottom Left", BOTTOM_LEFT), ("◢ Bottom Right", BOTTOM_RIGHT)]
+                    .into_iter()
+                    .map(|(name, corner)| (name.into(), self.erase_corners & corner != 0, NordOptions {erase_corners: self.erase_corners ^ corner, ..self_no_start.clone()}, is_model_enabled(self)))
+                    .collect()
+                );
+            }
+        } else {
+            background_row.extend([
+                (function_name, true, NordOptions {activation_function: self.activation_function.next(), ..self_no_start.clone()}, is_model_enabled(self)),
+                (refinement_name, self.mask_refinement == MaskRefinement::Refined, NordOptions {mask_refinement: self.mask_refinement.next(), ..self_no_start.clone()}, is_model_enabled(self)),
+                ("Tune Mask".into(), false, NordOptions {tune_mask: true, ..self_no_start.clone()}, is_model_enabled(self)),
+            ]);
+            option_2d_list.push(background_row);
+        }
+        // preset vec, if there is still a row left next to the one with the keep buttons
+        if option_2d_list.len() < 4 {
+            option_2d_list.push(self._generate_preset_row());
+        }
         option_2d_list
     }
 }
 
 
 
-#[derive(Clone, Debug, PartialEq, Copy)]
+/// Most dominant colors the eraser removes at once
+const MAX_ERASE_COLORS: u8 = 4;
+
+/// The dominant color algorithm, up to 3 configured models and the hybrid eraser fill one row of buttons.
+/// Discord allows 5 rows, so more rows would leave no room for the other options.
+const MAX_MODEL_BUTTONS: usize = 5;
+
+/// Cycles the output format of the options: config default -> every format -> config default
+fn next_output_format(format: Option<OutputFormat>) -> Option<OutputFormat> {
+    match format {
+        None => OutputFormat::from_u8(0),
+        Some(format) => OutputFormat::from_u8(format as u8 + 1),
+    }
+}
+
+/// Cycles through common qualities, starting and ending with the configured one
+fn next_quality(quality: Option<u8>) -> Option<u8> {
+    const QUALITIES: [u8; 4] = [95, 85, 75, 50];
+    match quality {
+        None => Some(QUALITIES[0]),
+        Some(quality) => QUALITIES.iter().copied().find(|&next| next < quality),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Copy, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct RgbColor {
-    r: u8,
-    g: u8,
-    b: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
 }
 
 impl RgbColor {
@@ -567,127 +971,122 @@ impl RgbColor {
             b: (new_b * 255.0) as u8,
         }
     }
-    fn color_distance(&self, c2: (u8, u8, u8)) -> f32 {
+    /// Euclidean distance of the sRGB values
+    pub fn color_distance(&self, other: &RgbColor) -> f32 {
         let (r1, g1, b1) = (self.r, self.g, self.b);
-        let (r2, g2, b2) = c2;
+        let (r2, g2, b2) = (other.r, other.g, other.b);
         let dr = r1 as f32 - r2 as f32;
         let dg = g1 as f32 - g2 as f32;
         let db = b1 as f32 - b2 as f32;
         (dr * dr + dg * dg + db * db).sqrt()
     }
 
-    pub fn from_hex(hex: &str) -> Result<Self, ParseIntError> {
-        let hex = hex.trim_start_matches("#");
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim().trim_start_matches("#");
+        if hex.len() != 6 || !hex.is_ascii() {
+            bail!("`{}` is not a color like #AF4453", hex);
+        }
         let r = u8::from_str_radix(&hex[0..2], 16)?;
         let g = u8::from_str_radix(&hex[2..4], 16)?;
         let b = u8::from_str_radix(&hex[4..6], 16)?;
         Ok(RgbColor {r, g, b})
     }
 
+    /// Parses colors separated by commas or whitespace, e.g. `#1e1e2e, #313244 #89b4fa`
+    pub fn from_hex_list(hex_list: &str) -> Result<Vec<Self>> {
+        const MAX_COLORS: usize = 16;
+        let colors = hex_list
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|hex| !hex.is_empty())
+            .map(RgbColor::from_hex)
+            .collect::<Result<Vec<_>>>()?;
+        if colors.is_empty() {
+            bail!("No colors given");
+        }
+        if colors.len() > MAX_COLORS {
+            bail!("At most {} colors are allowed, got {}", MAX_COLORS, colors.len());
+        }
+        Ok(colors)
+    }
+
     pub fn as_hex(&self) -> String {
         format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
     }
-}
 
-impl Display for RgbColor {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "#{:02x}{:02x}{:02x} (r: {} g: {} b {})", self.r, self.g, self.b, self.r, self.g, self.b)
+    pub fn to_rgba(self) -> Rgba<u8> {
+        Rgba([self.r, self.g, self.b, 255])
     }
 }
 
-struct PolarNight {}
-impl PolarNight {
-    const A: RgbColor = RgbColor {r: 46, g: 52, b: 64};
-    const B: RgbColor = RgbColor {r: 59, g: 66, b: 82};
-    const C: RgbColor = RgbColor {r: 67, g: 76, b: 94};
-    const D: RgbColor = RgbColor {r: 76, g: 86, b: 106};
-}
+impl TryFrom<String> for RgbColor {
+    type Error = anyhow::Error;
 
-// struct SnowStorm {}
-// impl SnowStorm {
-//     const A: RgbColor = RgbColor {r: 216, g: 222, b: 233};
-//     const B: RgbColor = RgbColor {r: 229, g: 233, b: 240};
-//     const C: RgbColor = RgbColor {r: 236, g: 239, b: 244};
-//     const D: RgbColor = RgbColor {r: 236, g: 239, b: 244};
-// }
-
-struct Frost {}
-// impl for #8fbcbb #88c0d0 #81a1c1 #5e81ac
-impl Frost {
-    const A: RgbColor = RgbColor {r: 143, g: 188, b: 187};
-    const B: RgbColor = RgbColor {r: 136, g: 192, b: 208};
-    const C: RgbColor = RgbColor {r: 129, g: 161, b: 193};
-    const D: RgbColor = RgbColor {r: 94, g: 129, b: 172};
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        RgbColor::from_hex(&hex)
+    }
 }
 
-
-pub fn apply_nord(mut _image: DynamicImage, options: NordOptions, info: &ImageInformation) -> DynamicImage {
-    let mut image = _image.clone();
-    println!("{:?}", image.dimensions());
-    //image = image.grayscale();
-    println!("Brightness of image is: {:.3}", info.brightness.average);
-
-    if options.erase_most_present_color {
-        if options.model != Models::Algorithm {
-            // Remove background with AI
-            // load AI model
-            let model_path = options.model.to_struct().path;
-            let environment = Environment::builder()
-            .with_name("background_removal")
-            .with_log_level(onnxruntime::LoggingLevel::Warning)
-            .build().unwrap();
-        
-            let session = environment
-                .new_session_builder().unwrap()
-                .with_optimization_level(GraphOptimizationLevel::Basic).unwrap()
-                .with_model_from_file(model_path).unwrap();
-            
-            let start = std::time::Instant::now();
-            let segmented_image = remove_background(session, image, &options);
-            println!("[Total] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
-            image = segmented_image;
-        } else {
-            //Remove most present color if above threshold
-            let mut mod_image = image.to_rgba8();
-            let (most_present_color_tuple, percentage) = (info.color_map.most_present_color, info.color_map.most_present_color_percentage);
-            let most_present_color = RgbColor {r: most_present_color_tuple.0, g: most_present_color_tuple.1, b: most_present_color_tuple.2};
-            if percentage >= options.erase_when_percentage {
-                // there is actually a color to remove -> remove it
-                remove_most_present_colors(&mut mod_image, most_present_color, 40.);
-                image = DynamicImage::from(mod_image);
-            }
-        }
-
-
+impl From<RgbColor> for String {
+    fn from(color: RgbColor) -> Self {
+        color.as_hex()
     }
+}
 
-    if options.invert {
-        image.invert();
-    }
-    let mut mod_image = image.to_rgba8();
-    if options.sepia {
-        apply_sepia(&mut mod_image);
-    }
-    if options.hue_rotate != 0.0 {
-        mod_image = DynamicImage::from(mod_image)
-            .huerotate(options.hue_rotate as i32)
-            .to_rgba8();
-    }
-    if options.nord {
-        apply_nord_filter(&mut mod_image, &options);
-    }
-    if options.background_color.is_some() {
-        let background_color = options.background_color.unwrap();
-        let mut background_image = RgbaImage::from_pixel(image.width(), image.height(), Rgba([background_color.r, background_color.g, background_color.b, 255]));
-        overlay(&mut background_image, &image, 0, 0);
-        mod_image = background_image;
+impl Display for RgbColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x} (r: {} g: {} b {})", self.r, self.g, self.b, self.r, self.g, self.b)
     }
-    if options.sepia || options.hue_rotate != 0.0 || options.nord || options.background_color.is_some() {
-        DynamicImage::from(mod_image)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn apply_nord(
+    image: DynamicImage,
+    source_hash: Option<&str>,
+    options: NordOptions,
+    pipeline: &Pipeline,
+    info: &ImageInformation,
+    palette: &Palette,
+    models: &ModelManager,
+    backdrop: Option<&DynamicImage>,
+) -> Result<DynamicImage> {
+    debug!("{:?}", image.dimensions());
+    debug!("Brightness of image is: {:.3}", info.brightness.average);
+
+    debug!("Pipeline: {:?}", pipeline.names());
+    // the blur is taken from the original image, before any filter changed it
+    let blurred = (options.background_color.is_some() && options.background_mode == BackgroundMode::Blur)
+        .then(|| blur_backdrop(&image));
+    let backdrop = match options.background_mode {
+        BackgroundMode::Blur => blurred.as_ref(),
+        BackgroundMode::Image => backdrop,
+        _ => None,
+    };
+    pipeline.apply(image, &FilterContext { options: &options, info, palette, models, backdrop, source_hash })
+}
+
+/// Removes the background either with the selected AI model or by erasing the most present color
+/// `source_hash` identifies the image while it's still the decoded source, which lets the mask be cached
+pub fn erase_background(image: DynamicImage, source_hash: Option<&str>, options: &NordOptions, info: &ImageInformation, models: &ModelManager) -> Result<DynamicImage> {
+    if options.model != BackgroundModel::Algorithm {
+        // Remove background with AI
+        let start = std::time::Instant::now();
+        let segmented_image = remove_background(models, image, source_hash, options, info)?;
+        debug!("[Total] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
+        Ok(segmented_image)
     } else {
-        image
+        //Remove most present colors if above threshold
+        let colors = erased_colors(info, options);
+        if colors.is_empty() {
+            return Ok(image);
+        }
+        // there is actually a color to remove -> remove it
+        let mut mod_image = image.to_rgba8();
+        remove_colors(&mut mod_image, &colors, options.color_metric.erase_metric(), options.erase_mode, options.erase_corners);
+        if options.cleanup.is_enabled() {
+            options.cleanup.apply_to_alpha(&mut mod_image);
+        }
+        Ok(DynamicImage::from(mod_image))
     }
-    
 }
 
 
@@ -701,15 +1100,76 @@ pub fn _tint_image(image: &mut RgbaImage, tint: Rgb<f32>) {
     }
 }
 
-pub fn apply_sepia(image: &mut RgbaImage) {
-    for Rgba([r, g, b, _]) in image.pixels_mut() {
-        let tr = (0.393 * *r as f32 + 0.769 * *g as f32 + 0.189 * *b as f32).min(255.0) as u8;
-        let tg = (0.349 * *r as f32 + 0.686 * *g as f32 + 0.168 * *b as f32).min(255.0) as u8;
-        let tb = (0.272 * *r as f32 + 0.534 * *g as f32 + 0.131 * *b as f32).min(255.0) as u8;
-        *r = tr;
-        *g = tg;
-        *b = tb;
-    }
+/// Mixes `from` and `to` (both 0-255), `amount` = 1 gives `to`
+fn mix(from: f32, to: f32, amount: f32) -> u8 {
+    (from + (to - from) * amount).clamp(0.0, 255.0) as u8
+}
+
+pub fn apply_invert(image: &mut RgbaImage, strength: f32) {
+    par_pixels_mut(image, |Rgba([r, g, b, _])| {
+        *r = mix(*r as f32, 255.0 - *r as f32, strength);
+        *g = mix(*g as f32, 255.0 - *g as f32, strength);
+        *b = mix(*b as f32, 255.0 - *b as f32, strength);
+    });
+}
+
+pub fn apply_sepia(image: &mut RgbaImage, strength: f32) {
+    par_pixels_mut(image, |Rgba([r, g, b, _])| {
+        let tr = (0.393 * *r as f32 + 0.769 * *g as f32 + 0.189 * *b as f32).min(255.0);
+        let tg = (0.349 * *r as f32 + 0.686 * *g as f32 + 0.168 * *b as f32).min(255.0);
+        let tb = (0.272 * *r as f32 + 0.534 * *g as f32 + 0.131 * *b as f32).min(255.0);
+        *r = mix(*r as f32, tr, strength);
+        *g = mix(*g as f32, tg, strength);
+        *b = mix(*b as f32, tb, strength);
+    });
+}
+
+/// Saturation gate used by the "Smart Dark" preset and the smart invert toggle
+pub const SMART_INVERT_SATURATION_GATE: f32 = 0.1;
+
+/// Inverts the OKLab lightness of every pixel while keeping its hue and chroma.
+/// With a `saturation_gate`, only pixels with a lower chroma are inverted (fading in below it),
+/// so that photos or logos inside of screenshots are left alone.
+pub fn apply_smart_invert(image: &mut RgbaImage, saturation_gate: Option<f32>, strength: f32) {
+    let cache = ColorCache::new();
+    par_pixels_mut(image, |Rgba([r, g, b, _])| {
+        let color = RgbColor { r: *r, g: *g, b: *b };
+        let inverted = cache.get_or_insert_with(color, || {
+            let lab = Oklab::from_rgb(&color);
+            let amount = strength * match saturation_gate {
+                Some(gate) => 1. - smoothstep(gate * 0.5, gate, lab.chroma()),
+                None => 1.,
+            };
+            Oklab { l: lab.l + amount * (1. - 2. * lab.l), ..lab }.to_rgb()
+        });
+        (*r, *g, *b) = (inverted.r, inverted.g, inverted.b);
+    });
+}
+
+/// Turns the hue of every pixel by `degrees` with the RGB rotation matrix of `image::imageops::huerotate`,
+/// which gives the established look of the presets. Unlike it, fractions of a degree count.
+pub fn apply_hue_rotate(image: &mut RgbaImage, degrees: f32) {
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let matrix = [
+        [0.213 + cos * 0.787 - sin * 0.213, 0.715 - cos * 0.715 - sin * 0.715, 0.072 - cos * 0.072 + sin * 0.928],
+        [0.213 - cos * 0.213 + sin * 0.143, 0.715 + cos * 0.285 + sin * 0.140, 0.072 - cos * 0.072 - sin * 0.283],
+        [0.213 - cos * 0.213 - sin * 0.787, 0.715 - cos * 0.715 + sin * 0.715, 0.072 + cos * 0.928 + sin * 0.072],
+    ];
+    par_pixels_mut(image, |Rgba([r, g, b, _])| {
+        let rgb = [*r as f64, *g as f64, *b as f64];
+        let [new_r, new_g, new_b] = matrix.map(|row| (row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]).clamp(0., 255.) as u8);
+        (*r, *g, *b) = (new_r, new_g, new_b);
+    });
+}
+
+/// Turns the hue of every pixel by `degrees` in OKLab, keeping lightness and chroma. Fractions of a degree count.
+pub fn apply_perceptual_hue_rotate(image: &mut RgbaImage, degrees: f32) {
+    let cache = ColorCache::new();
+    par_pixels_mut(image, |Rgba([r, g, b, _])| {
+        let color = RgbColor { r: *r, g: *g, b: *b };
+        let rotated = cache.get_or_insert_with(color, || Oklab::from_rgb(&color).rotate_hue(degrees).to_rgb());
+        (*r, *g, *b) = (rotated.r, rotated.g, rotated.b);
+    });
 }
 
 pub fn _apply_tone(image: &mut RgbaImage, target_color: Rgb<f32>, blend_factor: f32) {
@@ -733,29 +1193,24 @@ pub fn calculate_average_brightness(image: &RgbaImage) -> ImageInformation {
     image_information
 }
 
-pub fn apply_nord_filter(image: &mut RgbaImage, options: &NordOptions) {
-    let mut smallest_grey = f32::MAX;
-    let mut biggest_grey = f32::MIN;
-    let max_brightness = if options.erase_most_present_color {1.} else {0.85};
+/// OKLab chroma from which on a pixel is snapped to the accent colors of a palette
+const MIN_ACCENT_CHROMA: f32 = 0.06;
 
-    let contrast_colors = vec![
-        PolarNight::A, PolarNight::B, PolarNight::C, PolarNight::D,
-    ];
+pub fn apply_nord_filter(image: &mut RgbaImage, options: &NordOptions, palette: &Palette) {
+    let max_brightness = if options.erase_most_present_color {1.} else {0.85};
 
-    let colorful_colors = vec![
-        Frost::A, Frost::B, Frost::C, Frost::D,
-    ];
+    let contrast_colors = &palette.contrast;
+    let colorful_colors = &palette.accent;
 
-    for color in &contrast_colors {
+    for color in contrast_colors {
         println!("{} {} {} has brightness {:.3}", color.r, color.g, color.b, color.brightness());
     }
 
-    fn get_nearest_color<'a>(color: &RgbColor, all_colors: &'a [RgbColor]) -> &'a RgbColor {
+    fn get_nearest_color<'a>(color: &RgbColor, all_colors: &'a [RgbColor], metric: ColorMetric) -> &'a RgbColor {
         let mut min_distance = f32::MAX;
         let mut nearest_color = &all_colors[0];
-        let br = color.brightness();
         for c in all_colors.iter() {
-            let dist = (c.brightness() - br).abs();
+            let dist = metric.distance(c, color);
             if dist < min_distance {
                 min_distance = dist;
                 nearest_color = c;
@@ -764,59 +1219,49 @@ pub fn apply_nord_filter(image: &mut RgbaImage, options: &NordOptions) {
         nearest_color
     }
 
-    let mut cache: HashMap<(u8, u8, u8), (u8, u8, u8)> = HashMap::new();
-
-    for Rgba([r, g, b, _]) in image.pixels_mut() {
-        let key = (*r, *g, *b);
-        if let Some(&(cached_r, cached_g, cached_b)) = cache.get(&key) {
-            *r = cached_r;
-            *g = cached_g;
-            *b = cached_b;
-            continue;
-        }
-
-        let color = RgbColor { r: *r, g: *g, b: *b };
-        let current_pixel_br = color.brightness();
-        let grayscale_similarity = color.calculate_grayscale_similarity();
-
-        if grayscale_similarity < smallest_grey {
-            smallest_grey = grayscale_similarity;
-        }
-        if grayscale_similarity > biggest_grey {
-            biggest_grey = grayscale_similarity;
-        }
-
-        let darken_by = (current_pixel_br - max_brightness).max(0.0);
-        let adjusted_color = if darken_by > 0.0 {
+    let cache = ColorCache::new();
+    // colors above the maximum brightness are darkened before they are snapped or blended
+    let adjust = |color: RgbColor| {
+        let darken_by = (color.brightness() - max_brightness).max(0.0);
+        if darken_by > 0.0 {
             color.darken_rgb(darken_by)
         } else {
             color
-        };
+        }
+    };
 
-        let nearest_color = if grayscale_similarity < 0.25 {
-            get_nearest_color(&adjusted_color, &contrast_colors)
+    let snap = |color: RgbColor| cache.get_or_insert_with(color, || {
+        let adjusted_color = adjust(color);
+
+        // perceptual metrics decide by chroma, so that e.g. a light blue sky still counts as colorful
+        let is_colorful = if options.color_metric.is_perceptual() {
+            Oklab::from_rgb(&adjusted_color).chroma() >= MIN_ACCENT_CHROMA
         } else {
-            get_nearest_color(&adjusted_color, &colorful_colors)
+            color.calculate_grayscale_similarity() >= 0.25
         };
+        if is_colorful {
+            *get_nearest_color(&adjusted_color, colorful_colors, options.color_metric)
+        } else {
+            *get_nearest_color(&adjusted_color, contrast_colors, options.color_metric)
+        }
+    });
 
-        let strength = (1.0 - (current_pixel_br - nearest_color.brightness()).abs()) * 0.8;
+    let blend = |color: RgbColor, nearest_color: RgbColor| {
+        let adjusted_color = adjust(color);
+        let strength = (1.0 - (color.brightness() - nearest_color.brightness()).abs()) * options.nord_strength;
 
         let blended_r = (adjusted_color.rn() * (1.0 - strength) + nearest_color.rn() * strength) * 255.0;
         let blended_g = (adjusted_color.gn() * (1.0 - strength) + nearest_color.gn() * strength) * 255.0;
         let blended_b = (adjusted_color.bn() * (1.0 - strength) + nearest_color.bn() * strength) * 255.0;
 
-        let final_r = blended_r.min(255.0) as u8;
-        let final_g = blended_g.min(255.0) as u8;
-        let final_b = blended_b.min(255.0) as u8;
-
-        cache.insert(key, (final_r, final_g, final_b));
-
-        *r = final_r;
-        *g = final_g;
-        *b = final_b;
-    }
+        RgbColor {
+            r: blended_r.min(255.0) as u8,
+            g: blended_g.min(255.0) as u8,
+            b: blended_b.min(255.0) as u8,
+        }
+    };
 
-    println!("greyscale: {:.3} - {:.3}", smallest_grey, biggest_grey);
+    dither(image, options.dither, snap, blend);
 }
 
 fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
@@ -833,36 +1278,89 @@ fn map_distance_to_transparency(distance: f32, max_distance: f32) -> u8 {
     }
 }
 
-pub fn remove_most_present_colors(image: &mut RgbaImage, most_present_color: RgbColor, max_distance: f32) {
-    for pixel in image.pixels_mut() {
-        let Rgba([r, g, b, _a]) = *pixel;
-        let rgb = (r, g, b);
-        let distance = most_present_color.color_distance(rgb);
-
-        if distance < max_distance {
-            let new_alpha = map_distance_to_transparency(distance, max_distance);
-            *pixel = Rgba([r, g, b, new_alpha]);
+/// Share of the border pixels a further dominant color needs to count as background
+const MIN_ERASED_BORDER_SHARE: f64 = 0.1;
+
+/// The colors the eraser removes with their tolerance in sRGB units.
+/// Empty if the most present color isn't present enough to be the background.
+/// The most present color uses the erase distance, further dominant colors
+/// are widened by their spread, so that gradients are covered.
+/// Further colors are only erased if they are present enough and touch the border,
+/// so that the main colors of a subject in the middle are kept.
+pub fn erased_colors(info: &ImageInformation, options: &NordOptions) -> Vec<(RgbColor, f32)> {
+    if info.color_map.most_present_color_percentage < options.erase_when_percentage {
+        return Vec::new();
+    }
+    let (r, g, b) = info.color_map.most_present_color;
+    let mut colors = vec![(RgbColor { r, g, b }, options.erase_distance)];
+    let metric = options.color_metric.erase_metric();
+    let erase_distance = metric.scale_rgb_distance(options.erase_distance);
+    for dominant in &info.color_map.palette {
+        if colors.len() >= options.erase_colors as usize {
+            break;
+        }
+        // colors which the eraser already covers don't count
+        if colors.iter().any(|(color, _)| metric.distance(color, &dominant.color) < erase_distance) {
+            continue;
         }
+        if dominant.percentage < options.erase_when_percentage / 2. || dominant.border < MIN_ERASED_BORDER_SHARE {
+            continue;
+        }
+        let tolerance = (options.erase_distance + dominant.spread).min(options.erase_distance * 2.);
+        colors.push((dominant.color, tolerance));
     }
+    colors
 }
 
-fn _apply_gaussian_blur_to_alpha(image: &mut RgbaImage, sigma: f32) {
-    let (width, height) = image.dimensions();
-    let mut alpha_image = RgbaImage::new(width, height);
-
-    // Extract the alpha channel
-    for (x, y, pixel) in image.enumerate_pixels() {
-        alpha_image.put_pixel(x, y, Rgba([0, 0, 0, pixel[3]]));
+/// Makes pixels near any of `colors` transparent, each color with its own tolerance in sRGB units.
+/// Except for `EraseMode::Global`, only pixels connected to the border or the chosen corners
+/// through pixels near the colors are erased. White text or highlights inside the subject keep their color this way.
+pub fn remove_colors(image: &mut RgbaImage, colors: &[(RgbColor, f32)], metric: ColorMetric, mode: EraseMode, corners: u8) {
+    let alpha = color_distance_mask(image, colors, metric);
+    let filled = match mode {
+        EraseMode::Global => vec![true; alpha.as_raw().len()],
+        _ => {
+            let seeds = mode.seeds(image.width(), image.height(), corners);
+            flood_fill(image.width(), image.height(), &seeds, |index| alpha.as_raw()[index] < 255)
+        },
+    };
+    for ((pixel, filled), new_alpha) in image.pixels_mut().zip(filled).zip(alpha.as_raw()) {
+        if filled && *new_alpha < 255 {
+            pixel[3] = *new_alpha;
+        }
     }
+}
 
-    // Apply Gaussian blur to the alpha channel
-    let blurred_alpha_image = gaussian_blur_f32(&alpha_image, sigma);
+/// The alpha the color eraser gives every pixel, as a mask. The nearest of the colors decides.
+pub fn color_distance_mask(image: &RgbaImage, colors: &[(RgbColor, f32)], metric: ColorMetric) -> GrayImage {
+    let colors: Vec<(RgbColor, f32)> = colors
+        .iter()
+        .map(|&(color, max_distance)| (color, metric.scale_rgb_distance(max_distance)))
+        .collect();
+    let alpha: Vec<u8> = image
+        .as_raw()
+        .par_chunks_exact(4)
+        .map(|pixel| {
+            let pixel = RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] };
+            colors
+                .iter()
+                .map(|(color, max_distance)| {
+                    let distance = metric.distance(color, &pixel);
+                    if distance < *max_distance { map_distance_to_transparency(distance, *max_distance) } else { 255 }
+                })
+                .min()
+                .unwrap_or(255)
+        })
+        .collect();
+    GrayImage::from_raw(image.width(), image.height(), alpha).unwrap()
+}
 
-    // Update the image with the blurred alpha channel
-    for (x, y, blurred_pixel) in blurred_alpha_image.enumerate_pixels() {
-        let pixel = image.get_pixel_mut(x, y);
-        pixel[3] = blurred_pixel[3];
-    }
+/// A row of squares in the given colors, to show which colors were erased
+pub fn swatch_image(colors: &[RgbColor]) -> RgbaImage {
+    const SWATCH_SIZE: u32 = 48;
+    RgbaImage::from_fn(SWATCH_SIZE * colors.len().max(1) as u32, SWATCH_SIZE, |x, _| {
+        colors.get((x / SWATCH_SIZE) as usize).map_or(Rgba([0, 0, 0, 0]), |color| Rgba([color.r, color.g, color.b, 255]))
+    })
 }
 
 #[derive(Clone, Debug)]
@@ -878,7 +1376,7 @@ impl ImageInformation {
         ImageInformation {
             brightness: Brightness { average: 0.0, min: 0.0, max: 0.0 },
             grayscale_similarity: GrayScaleSimilarity { average: 0.0, min: 0.0, max: 0.0 },
-            color_map: ColorMap { most_present_color: (0, 0, 0), most_present_color_percentage: 0.0, amount: 0 },
+            color_map: ColorMap { most_present_color: (0, 0, 0), most_present_color_percentage: 0.0, amount: 0, palette: Vec::new() },
             image_type: None,
         }
     }
@@ -894,6 +1392,8 @@ pub struct ColorMap {
     pub most_present_color: (u8, u8, u8),
     pub most_present_color_percentage: f64,
     pub amount: u64,
+    /// dominant colors of the opaque pixels, most present first
+    pub palette: Vec<DominantColor>,
 }
 #[derive(Clone, Debug)]
 pub struct Brightness {
@@ -902,53 +1402,119 @@ pub struct Brightness {
     pub max: f32,
 }
 
-fn get_image_information(image: &RgbaImage) -> ImageInformation {
-    let mut total_brightness = 0.0;
-    let mut total_grayscale = 0.0;
-    let mut color_map: HashMap<(u8, u8, u8), u64> = HashMap::new();
-    let mut image_information = ImageInformation::new();
-    let mut min_brightness = f32::MAX;
-    let mut max_brightness = f32::MIN;
-    let mut min_grayscale = f32::MAX;
-    let mut max_grayscale = f32::MIN;
-    
-    let num_pixels = image.width() * image.height();
-    const SAMPLE_DISTANCE: usize = 50;
-    let pixel_amount = num_pixels / SAMPLE_DISTANCE.max(1) as u32;
+/// Dominant colors which are extracted from every image
+const PALETTE_SIZE: usize = 6;
+
+/// Distance in sRGB units up to which colors are merged into the most present color
+const DOMINANT_COLOR_TOLERANCE: f32 = 8.;
+
+/// Sums of the sampled pixels. Every thread collects its own, which are merged afterwards.
+struct PixelStatistics {
+    total_brightness: f32,
+    total_grayscale: f32,
+    min_brightness: f32,
+    max_brightness: f32,
+    min_grayscale: f32,
+    max_grayscale: f32,
+    color_map: HashMap<(u8, u8, u8), u64>,
+}
 
-    for (i, Rgba([r, g, b, a])) in image.pixels().enumerate() {
-        if i % SAMPLE_DISTANCE != 0 || *a <= 128 {
-            continue;
+impl PixelStatistics {
+    fn new() -> Self {
+        PixelStatistics {
+            total_brightness: 0.0,
+            total_grayscale: 0.0,
+            min_brightness: f32::MAX,
+            max_brightness: f32::MIN,
+            min_grayscale: f32::MAX,
+            max_grayscale: f32::MIN,
+            color_map: HashMap::new(),
         }
-        let pixel = RgbColor { r: *r, g: *g, b: *b };
+    }
+
+    fn add(mut self, pixel: RgbColor) -> Self {
         let brightness = pixel.brightness();
         let grayscale_similarity = pixel.calculate_grayscale_similarity();
 
-        total_brightness += brightness;
-        total_grayscale += grayscale_similarity;
+        self.total_brightness += brightness;
+        self.total_grayscale += grayscale_similarity;
+        self.min_brightness = self.min_brightness.min(brightness);
+        self.max_brightness = self.max_brightness.max(brightness);
+        self.min_grayscale = self.min_grayscale.min(grayscale_similarity);
+        self.max_grayscale = self.max_grayscale.max(grayscale_similarity);
 
-        if brightness < min_brightness {
-            min_brightness = brightness;
-        }
-        if brightness > max_brightness {
-            max_brightness = brightness;
-        }
-        if grayscale_similarity < min_grayscale {
-            min_grayscale = grayscale_similarity;
-        }
-        if grayscale_similarity > max_grayscale {
-            max_grayscale = grayscale_similarity;
-        }
+        *self.color_map.entry((pixel.r, pixel.g, pixel.b)).or_insert(0) += 1;
+        self
+    }
 
-        *color_map.entry((pixel.r, pixel.g, pixel.b)).or_insert(0) += 1;
+    fn merge(mut self, other: Self) -> Self {
+        self.total_brightness += other.total_brightness;
+        self.total_grayscale += other.total_grayscale;
+        self.min_brightness = self.min_brightness.min(other.min_brightness);
+        self.max_brightness = self.max_brightness.max(other.max_brightness);
+        self.min_grayscale = self.min_grayscale.min(other.min_grayscale);
+        self.max_grayscale = self.max_grayscale.max(other.max_grayscale);
+        for (color, count) in other.color_map {
+            *self.color_map.entry(color).or_insert(0) += count;
+        }
+        self
     }
+}
+
+/// The opaque pixels along the four edges of the image
+fn border_pixels(image: &RgbaImage) -> Vec<RgbColor> {
+    let (width, height) = image.dimensions();
+    let (right, bottom) = (width.saturating_sub(1), height.saturating_sub(1));
+    let top_and_bottom = (0..width).flat_map(|x| [(x, 0), (x, bottom)]);
+    let left_and_right = (1..bottom).flat_map(|y| [(0, y), (right, y)]);
+    top_and_bottom
+        .chain(left_and_right)
+        .map(|(x, y)| image.get_pixel(x, y))
+        .filter(|pixel| pixel[3] > 128)
+        .map(|pixel| RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] })
+        .collect()
+}
+
+fn get_image_information(image: &RgbaImage) -> ImageInformation {
+    let mut image_information = ImageInformation::new();
+
+    let num_pixels = image.width() * image.height();
+    const SAMPLE_DISTANCE: usize = 50;
+    let pixel_amount = num_pixels / SAMPLE_DISTANCE.max(1) as u32;
+
+    let PixelStatistics {
+        total_brightness, total_grayscale,
+        min_brightness, max_brightness,
+        min_grayscale, max_grayscale,
+        color_map,
+    } = image
+        .as_raw()
+        .par_chunks_exact(4)
+        .step_by(SAMPLE_DISTANCE)
+        .filter(|pixel| pixel[3] > 128)
+        .fold(PixelStatistics::new, |statistics, pixel| {
+            statistics.add(RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] })
+        })
+        .reduce(PixelStatistics::new, PixelStatistics::merge);
 
     let average_brightness = total_brightness / pixel_amount as f32;
     let average_grayscale_similarity = total_grayscale / pixel_amount as f32;
 
-    let (most_present_color, &most_present_color_count) = color_map.iter().max_by_key(|&(_, count)| count).unwrap_or((&(0, 0, 0), &0));
+    let (most_present_color, _) = color_map.iter().max_by_key(|&(_, count)| count).unwrap_or((&(0, 0, 0), &0));
+    // shades which can't be told apart from the most present color (e.g. JPEG noise) count towards it
+    let dominant = RgbColor { r: most_present_color.0, g: most_present_color.1, b: most_present_color.2 };
+    // the tolerance is given in sRGB units and doesn't depend on the options
+    let metric = ColorMetric::Rgb;
+    let tolerance = metric.scale_rgb_distance(DOMINANT_COLOR_TOLERANCE);
+    let most_present_color_count: u64 = color_map
+        .iter()
+        .filter(|(&(r, g, b), _)| metric.distance(&dominant, &RgbColor { r, g, b }) <= tolerance)
+        .map(|(_, count)| count)
+        .sum();
     let most_present_color_percentage = most_present_color_count as f64 / pixel_amount as f64;
     let color_amount = color_map.len() as u64;
+    let mut palette = dominant_colors(&color_map, PALETTE_SIZE);
+    measure_border(&mut palette, &border_pixels(image));
 
     image_information.brightness = Brightness {
         average: average_brightness,
@@ -966,6 +1532,7 @@ fn get_image_information(image: &RgbaImage) -> ImageInformation {
         most_present_color: *most_present_color,
         most_present_color_percentage,
         amount: color_amount,
+        palette,
     };
     // predict image type
     if 
@@ -981,113 +1548,386 @@ fn get_image_information(image: &RgbaImage) -> ImageInformation {
 
 
 
-fn preprocess_image(image: &DynamicImage, options: &NordOptions) -> Array4<f32> {
-    let model = options.model.to_struct();
-    let nwidth: u32 = model.width;
-    let nheight: u32 = model.height;
-    let resized = image.resize_exact(nwidth, nheight, image::imageops::FilterType::Nearest);
-    let rgb_image = resized.to_rgb8();
-
-    let mut input_tensor = Array4::<f32>::zeros((1, 3, nheight as usize, nwidth as usize));
-    for (y, x, pixel) in rgb_image.enumerate_pixels() {
-        input_tensor[[0, 0, x as usize, y as usize]] = pixel[0] as f32 / 255.0;
-        input_tensor[[0, 1, x as usize, y as usize]] = pixel[1] as f32 / 255.0;
-        input_tensor[[0, 2, x as usize, y as usize]] = pixel[2] as f32 / 255.0;
+/// Returns the mask of the image in the resolution of the model, covering the whole image.
+/// With `high_detail`, big images are also segmented in tiles and the mask has the size of the image.
+/// Without a `source_hash` the mask is not cached, e.g. for frames of an animation.
+fn segment_image(
+    models: &ModelManager,
+    image: &DynamicImage,
+    source_hash: Option<&str>,
+    options: &NordOptions
+) -> Result<Arc<GrayImage>> {
+    let Some(model_id) = options.model.model_id() else {
+        bail!("The dominant color algorithm has no model to segment the image with");
+    };
+    let model = registry().get(model_id)?;
+    let tile_budget = options.high_detail.then(|| models.tile_budget());
+    // the mask only depends on the source and the model, so clicking through other options reuses it
+    let key = source_hash.map(|hash| mask_key(hash, options, models.tile_budget())).transpose()?;
+    if let Some(mask) = key.as_ref().and_then(|key| models.masks().get(key)) {
+        debug!("[Segmentation] Reusing the cached mask");
+        return Ok(mask);
     }
+    let mask = Arc::new(segment_with_model(models, model, image, tile_budget)?);
+    if let Some(key) = key {
+        models.masks().insert(key, mask.clone());
+    }
+    Ok(mask)
+}
 
-    input_tensor
+/// Identifies the mask which the options make `segment_image` compute for the source
+fn mask_key(source_hash: &str, options: &NordOptions, tile_budget: (usize, f32)) -> Result<MaskKey> {
+    let Some(model_id) = options.model.model_id() else {
+        bail!("The dominant color algorithm has no model to segment the image with");
+    };
+    Ok(MaskKey::new(source_hash, registry().get(model_id)?, options.high_detail.then_some(tile_budget)))
 }
 
-fn segment_image<'a>(
-    session: &'a mut Session<'_>, 
+/// Segments the image in one pass, or additionally in tiles if a tile budget is given
+fn segment_with_model(
+    models: &ModelManager,
+    model: &ModelConfig,
     image: &DynamicImage,
-    options: &NordOptions
-) -> Result<
-    onnxruntime::tensor::OrtOwnedTensor<'a, 'a, f32, ndarray::Dim<ndarray::IxDynImpl>>,
-    Box<dyn std::error::Error>
-> 
-{
-    let input_tensor = preprocess_image(image, &options);
-    println!("Input tensor shape: {:?}", input_tensor.shape());
-    let input_array = vec![input_tensor];
-    let output: Vec<OrtOwnedTensor<f32, ndarray::Dim<ndarray::IxDynImpl>>> = session.run(input_array).unwrap();
-    println!("Output tensor shape: {:?}", output[0].shape());
-    let tensor = output.into_iter().next().unwrap();
-    Ok(tensor)
+    tile_budget: Option<(usize, f32)>,
+) -> Result<GrayImage> {
+    let global = run_segmentation(models, model, image)?;
+    let Some((max_tiles, overlap)) = tile_budget else {
+        return Ok(global);
+    };
+
+    let tiles = plan_tiles(image.dimensions(), (model.width, model.height), overlap, max_tiles);
+    if tiles.is_empty() {
+        return Ok(global);
+    }
+    debug!("[Segmentation] {} tiles of {}x{}", tiles.len(), tiles[0].width, tiles[0].height);
+    // every tile is queued before the first result is awaited, so that all inference workers take part.
+    // Only this thread waits for them, the threads of the rayon pool are left to the filters.
+    let pending = tiles
+        .iter()
+        .map(|tile| {
+            let (input_tensor, placement) = preprocess(&image.crop_imm(tile.left, tile.top, tile.width, tile.height), model);
+            Ok((models.submit(model, input_tensor)?, placement))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let masks = pending
+        .into_iter()
+        .zip(&tiles)
+        .map(|((run, placement), tile)| {
+            let mask = crop_mask(&run.wait()?, &placement)?;
+            Ok(DynamicImage::ImageLuma8(mask)
+                .resize_exact(tile.width, tile.height, image::imageops::FilterType::Triangle)
+                .to_luma8())
+        })
+        .collect::<Result<Vec<GrayImage>>>()?;
+    let feather = (tiles[0].width.min(tiles[0].height) as f32 * overlap) as u32;
+    let mut blender = TileBlender::new(image.width(), image.height(), feather);
+    for (tile, mask) in tiles.iter().zip(&masks) {
+        blender.add(tile, mask);
+    }
+    Ok(blender.finish(&global))
 }
 
+/// Runs `model` once over the whole image and returns the mask in the resolution of the model
+fn run_segmentation(models: &ModelManager, model: &ModelConfig, image: &DynamicImage) -> Result<GrayImage> {
+    let (input_tensor, placement) = preprocess(image, model);
+    debug!("Input tensor shape: {:?}, image placed at {:?}", input_tensor.shape(), placement);
+    let tensor = models.run(model, input_tensor)?;
+    debug!("Output tensor shape: {:?}", tensor.shape());
+    crop_mask(&tensor, &placement)
+}
 
+
+/// Applies the mask of a model to the image.
+/// `color_alpha` is the alpha of the color eraser, which is fused with the mask for the hybrid eraser.
 fn apply_mask(
     image: &DynamicImage, 
-    mask: &onnxruntime::tensor::OrtOwnedTensor<f32, ndarray::Dim<ndarray::IxDynImpl>>,
+    mask: &GrayImage,
+    color_alpha: Option<&GrayImage>,
     options: &NordOptions
 ) -> DynamicImage {
     let (orig_width, orig_height) = image.dimensions();
-    let mask_width = mask.shape()[2] as u32;
-    let mask_height = mask.shape()[3] as u32;
-
-    // Convert the mask to a Vec<u8> by scaling f32 values to u8
-    let mask_data: Vec<u8> = mask
-    .to_slice()
-    .unwrap()
-    .iter()
-    .map(|&v| (v * 255.0).min(255.0).max(0.0) as u8)
-    .collect();
+    let source = image.to_rgba8();
 
     // Ensure mask dimensions match image dimensions
-    let resized_mask = DynamicImage::ImageLuma8(
-        image::GrayImage::from_raw(mask_width, mask_height, mask_data).unwrap()
-    )
-        .resize_exact(orig_width, orig_height, image::imageops::FilterType::Nearest)
-        .to_luma8();
+    let mut resized_mask = upsample_mask(mask, &source, options.mask_refinement);
+    if let Some(color_alpha) = color_alpha {
+        resized_mask = fuse_masks(&resized_mask, color_alpha, options.fusion, refinement_radius(mask, &source), options.fusion_weight);
+    }
 
     let mut masked_image = RgbaImage::new(orig_width, orig_height);
 
-    // choose activation function
-    let mut activation_function: fn(u8) -> u8 = |x| x;
-    let sigmoid = |x: u8| -> u8 {
-        if x < 5 { return 0 } else if x > 250 { return 255 }
-        let x = x as f32 / 255.0; // Normalize to range [0, 1]
-        let sigmoid_value = 255.0 * (
-            (1.0) / ( 1.0+(( (x-0.5) / -0.1 ).exp()) )
-        );
-        sigmoid_value as u8
-    };
-
-    if options.activation_function == ActivationFunction::Sigmoid {
-        activation_function = sigmoid;
+    // the activation function only depends on the mask value, so it is evaluated once per value
+    let activation = options.activation_function.lookup_table(options.mask_center, options.mask_steepness);
+    let mut alpha_mask = resized_mask;
+    alpha_mask.par_iter_mut().for_each(|value| *value = activation[*value as usize]);
+    if options.cleanup.is_enabled() {
+        let start = std::time::Instant::now();
+        options.cleanup.apply(&mut alpha_mask);
+        debug!("[Cleanup] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
     }
     // time start
     let start = std::time::Instant::now();
-    masked_image.chunks_exact_mut(4).enumerate().for_each(|(index, pixel)| {
-        let x = (index as u32) % orig_width;
-        let y = (index as u32) / orig_width;
-        let pixel_value = image.get_pixel(x, y);
-        let mask_value = resized_mask.get_pixel(x, y)[0];
-        
-        let [r, g, b, a] = pixel_value.0;
-        if a < mask_value {
-            pixel.copy_from_slice(&[r, g, b, a]);
-            return;
+    par_rows_mut(&mut masked_image, |y, row| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let x = x as u32;
+            let pixel_value = source.get_pixel(x, y);
+            let mask_value = alpha_mask.get_pixel(x, y)[0];
+
+            let [r, g, b, a] = pixel_value.0;
+            // never make transparent pixels of the source visible
+            let alpha = mask_value.min(a);
+            pixel.copy_from_slice(&[r, g, b, alpha]);
         }
-        let alpha = activation_function(mask_value);
-        pixel.copy_from_slice(&[r, g, b, alpha]);
     });
     println!("[Masking-loop] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
+    if options.mask_refinement == MaskRefinement::Refined {
+        decontaminate_colors(&mut masked_image, refinement_radius(mask, &source));
+    }
     let img = DynamicImage::ImageRgba8(masked_image);
     img
 }
 
 
-pub fn remove_background<'a>(mut session: Session<'_>, image: DynamicImage, options: &NordOptions) -> DynamicImage {
+pub fn remove_background(models: &ModelManager, image: DynamicImage, source_hash: Option<&str>, options: &NordOptions, info: &ImageInformation) -> Result<DynamicImage> {
     // start time
     let start = std::time::Instant::now();
     // generates black-white mask
-    let mask = segment_image(&mut session, &image, &options).unwrap();
+    let mask = segment_image(models, &image, source_hash, options)?;
     println!("[Segmentation] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
     let start = std::time::Instant::now();
+    // the hybrid eraser only uses the color when it is present enough to be the background
+    let colors = if options.model.is_hybrid() { erased_colors(info, options) } else { Vec::new() };
+    let color_alpha = if colors.is_empty() {
+        None
+    } else {
+        Some(color_distance_mask(&image.to_rgba8(), &colors, options.color_metric.erase_metric()))
+    };
     // apply mask to image
-    let segmented_image = apply_mask(&image, &mask, &options);
+    let segmented_image = apply_mask(&image, &mask, color_alpha.as_ref(), &options);
     println!("[Masking] Time taken: {:.3} seconds", start.elapsed().as_secs_f32());
-    segmented_image
+    Ok(segmented_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+
+    use super::*;
+
+    /// Options where every field differs from the default, with values the custom id stores exactly
+    fn every_field_set() -> NordOptions {
+        NordOptions {
+            invert: false,
+            smart_invert: true,
+            hue_rotate: 90.5,
+            sepia: false,
+            nord: false,
+            erase_most_present_color: true,
+            erase_when_percentage: 0.25,
+            auto_adjust: false,
+            start: true,
+            model: BackgroundModel::Hybrid(2),
+            activation_function: ActivationFunction::Tanh,
+            background_color: Some(RgbColor { r: 12, g: 34, b: 56 }),
+            background_mode: BackgroundMode::Image,
+            background_image: 513,
+            palette: 300,
+            color_metric: ColorMetric::Rgb,
+            dither: DitherMode::Bayer,
+            invert_strength: 0.5,
+            sepia_strength: 0.25,
+            nord_strength: 0.75,
+            erase_distance: 55.,
+            mask_refinement: MaskRefinement::Bicubic,
+            mask_center: 0.35,
+            mask_steepness: 12.5,
+            high_detail: true,
+            erase_mode: EraseMode::Corners,
+            erase_corners: TOP_LEFT | BOTTOM_RIGHT,
+            erase_colors: 3,
+            fusion: MaskFusion::Blend,
+            fusion_weight: 0.6,
+            cleanup: MaskCleanup { min_island: 0.005, max_hole: 0.02, grow: -3, feather: 1.5 },
+            tune: true,
+            tune_mask: true,
+            tune_cleanup: true,
+            layout: Layout::Background,
+            output_format: Some(OutputFormat::Jpeg),
+            quality: Some(85),
+        }
+    }
+
+    /// Compares every field, `PartialEq` of the options ignores the ones which steer the buttons
+    fn assert_round_trip(options: &NordOptions) {
+        let custom_id = options.make_nord_custom_id(&1234567890123456789, true, Some(42));
+        assert!(custom_id.len() <= 100, "custom id is {} characters long", custom_id.len());
+        let decoded = NordOptions::from_custom_id(&custom_id).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", options));
+    }
+
+    #[test]
+    fn options_are_equal_when_they_give_the_same_result() {
+        let options = every_field_set();
+        let steered = NordOptions { start: false, auto_adjust: true, tune: false, tune_mask: false, tune_cleanup: false, layout: Layout::Simple, ..options.clone() };
+        assert_eq!(steered, options);
+        assert_ne!(NordOptions { palette: 301, ..options.clone() }, options);
+        assert_ne!(NordOptions { dither: DitherMode::None, ..options.clone() }, options);
+        assert_ne!(NordOptions { cleanup: MaskCleanup::default(), ..options.clone() }, options);
+        assert_ne!(NordOptions { quality: Some(90), ..options.clone() }, options);
+    }
+
+    #[test]
+    fn presets_match_colors_by_brightness() {
+        for preset in NordPreset::iter() {
+            let options = NordOptions::from_preset(preset, &NordOptions::default());
+            assert_eq!(options.color_metric, ColorMetric::Brightness, "{:?}", preset);
+            assert_eq!(options.color_metric.erase_metric(), ColorMetric::Rgb);
+        }
+    }
+
+    #[test]
+    fn presets_keep_the_picked_palette() {
+        let options = NordOptions { palette: 3, ..NordOptions::from_preset(NordPreset::Nord, &NordOptions::default()) };
+        assert!(options.is_preset(NordPreset::Nord));
+        assert!(!NordOptions { sepia: false, ..options.clone() }.is_preset(NordPreset::Nord));
+    }
+
+    #[test]
+    fn mask_key_ignores_options_applied_after_segmentation() {
+        let options = NordOptions { model: BackgroundModel::Onnx(1), ..every_field_set() };
+        let key = |options: &NordOptions| mask_key("abc", options, (16, 0.25)).unwrap();
+        assert_eq!(key(&options), key(&NordOptions { activation_function: ActivationFunction::Sigmoid, ..options.clone() }));
+        assert_eq!(key(&options), key(&NordOptions { background_color: None, background_mode: BackgroundMode::Blur, ..options.clone() }));
+        assert_eq!(key(&options), key(&NordOptions { model: BackgroundModel::Hybrid(1), ..options.clone() }));
+        assert_ne!(key(&options), key(&NordOptions { model: BackgroundModel::Onnx(2), ..options.clone() }));
+        assert_ne!(key(&options), key(&NordOptions { high_detail: !options.high_detail, ..options.clone() }));
+        assert!(mask_key("abc", &NordOptions { model: BackgroundModel::Algorithm, ..options }, (16, 0.25)).is_err());
+    }
+
+    #[test]
+    fn custom_id_round_trips_every_field() {
+        assert_round_trip(&every_field_set());
+        assert_round_trip(&NordOptions { model: BackgroundModel::Onnx(1), ..every_field_set() });
+    }
+
+    #[test]
+    fn custom_id_round_trips_without_background() {
+        assert_round_trip(&NordOptions { background_color: None, background_mode: BackgroundMode::default(), background_image: 0, ..every_field_set() });
+        assert_round_trip(&NordOptions::default());
+        assert_round_trip(&NordOptions::new());
+    }
+
+    #[test]
+    fn every_layout_fits_into_five_rows() {
+        for layout in [Layout::Simple, Layout::Colors, Layout::Background] {
+            for erase_mode in [EraseMode::Global, EraseMode::Border, EraseMode::Corners] {
+                for model in [BackgroundModel::Algorithm, BackgroundModel::Onnx(1), BackgroundModel::Hybrid(1)] {
+                    for start in [false, true] {
+                        let options = NordOptions { layout, erase_mode, model, start, ..every_field_set() };
+                        assert!(options.build_componets(1, true).len() <= 5, "{:?}", options);
+                    }
+                }
+            }
+        }
+    }
+
+    fn hue(color: &RgbColor) -> f32 {
+        let lab = Oklab::from_rgb(color);
+        lab.b.atan2(lab.a).to_degrees()
+    }
+
+    #[test]
+    fn smart_invert_darkens_white_and_keeps_the_hue() {
+        let light_blue = RgbColor { r: 200, g: 220, b: 250 };
+        let mut image = RgbaImage::from_fn(2, 1, |x, _| if x == 0 { Rgba([255, 255, 255, 255]) } else { Rgba([200, 220, 250, 255]) });
+        apply_smart_invert(&mut image, Some(SMART_INVERT_SATURATION_GATE), 1.);
+        let &Rgba([r, g, b, _]) = image.get_pixel(0, 0);
+        assert!(r < 10 && r == g && g == b, "white became {:?}", (r, g, b));
+        let &Rgba([r, g, b, a]) = image.get_pixel(1, 0);
+        let inverted = RgbColor { r, g, b };
+        assert!(inverted.brightness() < 0.3, "{:?}", inverted);
+        assert!((hue(&inverted) - hue(&light_blue)).abs() < 5., "{} instead of {}", hue(&inverted), hue(&light_blue));
+        assert_eq!(a, 255);
+    }
+
+    #[test]
+    fn smart_invert_keeps_saturated_colors() {
+        let colors = [Rgba([220, 30, 30, 255]), Rgba([30, 120, 230, 128]), Rgba([40, 200, 60, 255])];
+        let mut image = RgbaImage::from_fn(3, 1, |x, _| colors[x as usize]);
+        let original = image.clone();
+        apply_smart_invert(&mut image, Some(SMART_INVERT_SATURATION_GATE), 1.);
+        assert_eq!(image, original);
+    }
+
+    #[test]
+    fn hue_rotation_keeps_the_look_of_the_image_crate() {
+        let image = RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * 16) as u8, (y * 16) as u8, 200, 255]));
+        let mut rotated = image.clone();
+        apply_hue_rotate(&mut rotated, 180.);
+        assert_eq!(rotated, image::imageops::huerotate(&image, 180));
+        // fractions of a degree count
+        let mut fraction = image.clone();
+        apply_hue_rotate(&mut fraction, 180.5);
+        assert_ne!(fraction, rotated);
+    }
+
+    /// Mean of all channels of the image after the nord filter
+    fn nord_mean(image: &RgbaImage, dither: DitherMode) -> f32 {
+        let mut image = image.clone();
+        apply_nord_filter(&mut image, &NordOptions { dither, ..NordOptions::default() }, &Palette::by_id(0));
+        image.pixels().map(|pixel| pixel[0] as f32 + pixel[1] as f32 + pixel[2] as f32).sum::<f32>() / (image.len() / 4 * 3) as f32
+    }
+
+    #[test]
+    fn dithering_a_bright_gradient_with_a_dark_palette_keeps_the_mean() {
+        // only the dark Polar Night colors are in reach of the gray gradient
+        let image = RgbaImage::from_fn(128, 32, |x, _| {
+            let value = 160 + (x * 95 / 127) as u8;
+            Rgba([value, value, value, 255])
+        });
+        let expected = nord_mean(&image, DitherMode::None);
+        for mode in [DitherMode::FloydSteinberg, DitherMode::Atkinson, DitherMode::Bayer] {
+            let mean = nord_mean(&image, mode);
+            assert!((mean - expected).abs() < 8., "{:?} gave a mean of {} instead of {}", mode, mean, expected);
+        }
+    }
+
+    /// Erases up to three colors of the image with the dominant color eraser
+    fn erased(image: &RgbaImage) -> Vec<RgbColor> {
+        let options = NordOptions { erase_colors: 3, ..NordOptions::from_preset(NordPreset::StaticBackground, &NordOptions::default()) };
+        erased_colors(&get_image_information(image), &options).into_iter().map(|(color, _)| color).collect()
+    }
+
+    #[test]
+    fn colors_of_a_centered_subject_are_kept() {
+        let white = Rgba([255, 255, 255, 255]);
+        // a red shirt and a skin colored face in the middle of a white background
+        let image = RgbaImage::from_fn(200, 200, |x, y| match (x, y) {
+            (50..150, 100..170) => Rgba([200, 30, 30, 255]),
+            (70..130, 30..100) => Rgba([230, 180, 150, 255]),
+            _ => white,
+        });
+        assert_eq!(erased(&image), vec![RgbColor { r: 255, g: 255, b: 255 }]);
+    }
+
+    #[test]
+    fn background_colors_along_the_border_are_erased() {
+        // white and light blue halves with a dark subject in the middle
+        let image = RgbaImage::from_fn(200, 200, |x, y| match (x, y) {
+            (70..130, 70..130) => Rgba([20, 20, 20, 255]),
+            (0..100, _) => Rgba([255, 255, 255, 255]),
+            _ => Rgba([150, 190, 250, 255]),
+        });
+        let colors = erased(&image);
+        assert_eq!(colors.len(), 2, "{:?}", colors);
+        assert!(colors.iter().all(|color| color.brightness() > 0.5), "{:?}", colors);
+    }
+
+    #[test]
+    fn custom_id_of_another_version_is_rejected() {
+        let custom_id = NordOptions::default().make_nord_custom_id(&1, false, None);
+        let mut bytes = STANDARD_NO_PAD.decode(custom_id.split('-').nth(1).unwrap()).unwrap();
+        bytes[0] = bytes[0].wrapping_sub(1);
+        assert!(NordOptions::from_custom_id(&format!("darken-{}-1", STANDARD_NO_PAD.encode(bytes))).is_err());
+    }
 }
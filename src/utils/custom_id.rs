@@ -0,0 +1,92 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+
+// Discord limits custom ids to 100 characters. The options of a button are therefore packed
+// into bytes and base64 encoded. The standard alphabet is used since it contains no `-`,
+// which separates the parts of a custom id.
+
+/// Bumped whenever the byte layout changes, so that old buttons are rejected instead of misread
+pub const CUSTOM_ID_VERSION: u8 = 16;
+
+pub struct CustomIdWriter {
+    bytes: Vec<u8>,
+}
+
+impl CustomIdWriter {
+    pub fn new() -> Self {
+        CustomIdWriter { bytes: vec![CUSTOM_ID_VERSION] }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn f32(&mut self, value: f32) -> &mut Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Packs up to 16 booleans into two bytes
+    pub fn flags(&mut self, flags: &[bool]) -> &mut Self {
+        let packed = flags.iter().enumerate().fold(0u16, |acc, (i, &flag)| acc | ((flag as u16) << i));
+        self.u16(packed)
+    }
+
+    pub fn encode(&self) -> String {
+        STANDARD_NO_PAD.encode(&self.bytes)
+    }
+}
+
+impl Default for CustomIdWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct CustomIdReader {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl CustomIdReader {
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let bytes = STANDARD_NO_PAD.decode(encoded)?;
+        match bytes.first() {
+            Some(&CUSTOM_ID_VERSION) => Ok(CustomIdReader { bytes, position: 1 }),
+            Some(version) => bail!("Unsupported custom id version: {}", version),
+            None => bail!("Empty custom id"),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some(slice) = self.bytes.get(self.position..self.position + N) else {
+            bail!("Custom id ended after {} bytes", self.bytes.len());
+        };
+        self.position += N;
+        Ok(slice.try_into()?)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    /// Unpacks booleans written with [`CustomIdWriter::flags`]
+    pub fn flags<const N: usize>(&mut self) -> Result<[bool; N]> {
+        let packed = self.u16()?;
+        Ok(std::array::from_fn(|i| packed & (1 << i) != 0))
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+/// Values which buttons refer to by a small id, like custom palettes or uploaded backgrounds.
+/// Ids are handed out in turn and wrap around at the end of their range, skipping ids which are still in use,
+/// so that a button never shows a value which was stored after it was created while its own value is kept.
+/// Once `capacity` is reached, the value which was stored first is dropped.
+pub struct IdStore<V> {
+    values: HashMap<u16, V>,
+    /// ids of the values, oldest first
+    order: VecDeque<u16>,
+    ids: Range<u16>,
+    next: u16,
+    capacity: usize,
+}
+
+impl<V> IdStore<V> {
+    /// `capacity` has to be smaller than the range, so that there is always a free id
+    pub fn new(ids: Range<u16>, capacity: usize) -> Self {
+        assert!(capacity > 0 && capacity < ids.len(), "capacity {} doesn't fit into the ids {:?}", capacity, ids);
+        IdStore { values: HashMap::new(), order: VecDeque::new(), next: ids.start, ids, capacity }
+    }
+
+    pub fn get(&self, id: u16) -> Option<&V> {
+        self.values.get(&id)
+    }
+
+    /// Id of the first value for which `predicate` is true
+    pub fn find(&self, predicate: impl Fn(&V) -> bool) -> Option<u16> {
+        self.order.iter().copied().find(|id| predicate(&self.values[id]))
+    }
+
+    /// Stores the value under a free id and returns it
+    pub fn insert(&mut self, value: V) -> u16 {
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+        let id = loop {
+            let id = self.next;
+            self.next = if id + 1 >= self.ids.end { self.ids.start } else { id + 1 };
+            if !self.values.contains_key(&id) {
+                break id;
+            }
+        };
+        self.values.insert(id, value);
+        self.order.push_back(id);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_wrap_around_without_reusing_stored_ones() {
+        let mut store = IdStore::new(10..14, 3);
+        assert_eq!([store.insert('a'), store.insert('b'), store.insert('c')], [10, 11, 12]);
+        // 'a' is dropped as the oldest value, the next id is still 13
+        assert_eq!(store.insert('d'), 13);
+        assert_eq!(store.get(10), None);
+        // wraps around to the id which became free
+        assert_eq!(store.insert('e'), 10);
+        assert_eq!(store.get(11), None);
+        assert_eq!([store.get(12), store.get(13), store.get(10)], [Some(&'c'), Some(&'d'), Some(&'e')]);
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn oldest_value_is_dropped_first() {
+        let mut store = IdStore::new(0..100, 2);
+        let first = store.insert("first");
+        let second = store.insert("second");
+        store.insert("third");
+        assert_eq!(store.get(first), None);
+        assert_eq!(store.get(second), Some(&"second"));
+        assert_eq!(store.find(|value| *value == "third"), Some(2));
+    }
+}
@@ -1,33 +1,92 @@
-use std::time::Duration;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use bytes::Bytes;
 use image::DynamicImage;
-use tokio::sync::RwLock;
-use lru_time_cache::LruCache;
+use tokio::sync::Mutex;
 
+use crate::utils::byte_lru::ByteLru;
 // use colors.rs
 use crate::utils::colors::ImageInformation;
 
+/// A decoded image with its information, shared between everything that works on it
+#[derive(Clone)]
+pub struct CachedImage {
+    pub image: Arc<DynamicImage>,
+    pub info: Arc<ImageInformation>,
+    /// hex encoded SHA-256 of the file as uploaded, identifies the source of cached results
+    pub source_hash: String,
+    /// the file as uploaded, kept for GIFs and WebPs which may be animated and are decoded again frame by frame
+    pub source: Option<Bytes>,
+}
+
+impl CachedImage {
+    /// Decoded pixels and the kept file
+    fn size(&self) -> usize {
+        self.image.as_bytes().len() + self.source.as_ref().map_or(0, |source| source.len())
+    }
+}
+
+/// How well the cache is doing since the start
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
 
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{} hits, {} misses, {} evictions, {} images with {:.1} MB",
+            self.hits, self.misses, self.evictions, self.entries, self.bytes as f64 / 1024. / 1024.,
+        )
+    }
+}
 
+/// Keeps decoded attachments by their id. Attachments can't be edited, so an id always refers to the same image,
+/// unlike their URLs which carry expiring parameters. The least recently used images are dropped once the decoded
+/// pixels take more than the given bytes.
 pub struct ImageCache {
-    pub cache: RwLock<LruCache<String, (DynamicImage, ImageInformation)>>,
+    cache: Mutex<ByteLru<u64, CachedImage>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl ImageCache {
-    pub fn new(capacity: usize, ttl: Duration) -> Self {
+    pub fn new(max_bytes: usize) -> Self {
         Self {
-            cache: RwLock::new(LruCache::with_expiry_duration_and_capacity(ttl, capacity)),
+            cache: Mutex::new(ByteLru::new(max_bytes)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
         }
     }
 
-    pub async fn insert(&self, key: String, image: DynamicImage, info: ImageInformation) {
-        let cache = self.cache.write();
-        cache.await.insert(key, (image, info));
+    pub async fn insert(&self, attachment_id: u64, image: CachedImage) {
+        let size = image.size();
+        let evicted = self.cache.lock().await.insert(attachment_id, image, size);
+        self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    pub async fn get(&self, attachment_id: u64) -> Option<CachedImage> {
+        let cached = self.cache.lock().await.get(&attachment_id).cloned();
+        let counter = if cached.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
     }
 
-    pub async fn get(&self, key: &str) -> Option<(DynamicImage, ImageInformation)> {
-        let cache = self.cache.write();
-        cache.await.get(key).map(|(image, info)| {
-            (image.clone(), info.clone())
-        })
+    pub async fn stats(&self) -> CacheStats {
+        let cache = self.cache.lock().await;
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: cache.len(),
+            bytes: cache.bytes(),
+        }
     }
-}
\ No newline at end of file
+}
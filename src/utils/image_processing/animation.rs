@@ -0,0 +1,154 @@
+use anyhow::{anyhow, bail, Result};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat};
+use std::io::Cursor;
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+use crate::config::AnimationConfig;
+use crate::utils::image_processing::decode_limits;
+
+/// The frames of an animated image, each with its own delay.
+/// Frames are full-sized, the decoders already composited them onto the canvas.
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    pub loop_count: LoopCount,
+}
+
+impl Animation {
+    /// Decodes an animated GIF or WebP.
+    /// Returns `None` for other formats and for images with only one frame.
+    /// The canvas is checked against the `decode_limits` before the first frame is decoded.
+    pub fn decode(bytes: &[u8], limits: &AnimationConfig) -> Result<Option<Animation>> {
+        match image::guess_format(bytes)? {
+            ImageFormat::Gif => {
+                let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+                decoder.set_limits(decode_limits())?;
+                Self::collect(decoder, limits)
+            },
+            ImageFormat::WebP => {
+                let mut decoder = WebPDecoder::new(Cursor::new(bytes))?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                decoder.set_limits(decode_limits())?;
+                Self::collect(decoder, limits)
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the frames one by one, so that decoding stops as soon as a limit is hit
+    fn collect<'a>(decoder: impl AnimationDecoder<'a>, limits: &AnimationConfig) -> Result<Option<Animation>> {
+        let loop_count = decoder.loop_count();
+        let mut frames = Vec::new();
+        let mut total_pixels: u64 = 0;
+        for frame in decoder.into_frames() {
+            let frame = frame?;
+            if frames.len() >= limits.max_frames {
+                bail!("The animation has more than {} frames, which is the most I can handle", limits.max_frames);
+            }
+            total_pixels += frame.buffer().width() as u64 * frame.buffer().height() as u64;
+            if total_pixels > limits.max_pixels {
+                bail!("The animation has more than {} pixels in total, which is the most I can handle", limits.max_pixels);
+            }
+            frames.push(frame);
+        }
+        if frames.len() < 2 {
+            return Ok(None);
+        }
+        Ok(Some(Animation { frames, loop_count }))
+    }
+
+    /// Replaces every frame with `f(frame)`, keeping position and delay; the first error is returned.
+    /// The frames are processed one after another on the calling thread, since `f` may wait for the
+    /// inference workers, which would stall the rayon pool the pixel filters of every request share.
+    pub fn map_frames(self, f: impl Fn(DynamicImage) -> Result<DynamicImage>) -> Result<Animation> {
+        let frames = self.frames
+            .into_iter()
+            .map(|frame| {
+                let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+                let image = f(DynamicImage::from(frame.into_buffer()))?;
+                Ok(Frame::from_parts(image.to_rgba8(), left, top, delay))
+            })
+            .collect::<Result<Vec<Frame>>>()?;
+        Ok(Animation { frames, ..self })
+    }
+
+    /// Encodes the animation as GIF, which keeps the timing and looping of the source
+    pub fn encode_gif(self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        {
+            let mut encoder = GifEncoder::new_with_speed(&mut buffer, 10);
+            encoder.set_repeat(match self.loop_count {
+                LoopCount::Infinite => Repeat::Infinite,
+                LoopCount::Finite(count) => Repeat::Finite(count.get().min(u16::MAX as u32) as u16),
+            })?;
+            encoder.encode_frames(self.frames)?;
+        }
+        Ok(buffer)
+    }
+
+    /// Encodes the animation as WebP, which keeps the alpha channel. `quality` is `None` for lossless.
+    pub fn encode_webp(self, quality: Option<u8>) -> Result<Vec<u8>> {
+        let Some(first) = self.frames.first() else {
+            bail!("The animation has no frames");
+        };
+        let (width, height) = first.buffer().dimensions();
+        let mut config = WebPConfig::new().map_err(|_| anyhow!("Failed to set up the WebP encoder"))?;
+        config.lossless = quality.is_none() as i32;
+        config.alpha_compression = quality.is_some() as i32;
+        config.quality = quality.map_or(75, |quality| quality.clamp(1, 100)) as f32;
+
+        let mut encoder = AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(match self.loop_count {
+            LoopCount::Infinite => 0,
+            LoopCount::Finite(count) => count.get().min(i32::MAX as u32) as i32,
+        });
+        // the timestamps are the start of each frame in milliseconds
+        let mut timestamp = 0;
+        for frame in &self.frames {
+            encoder.add_frame(AnimFrame::from_rgba(frame.buffer().as_raw(), width, height, timestamp));
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            timestamp += (numerator / denominator.max(1)) as i32;
+        }
+        let encoded = encoder
+            .try_encode()
+            .map_err(|e| anyhow!("Failed to encode the animation: {:?}", e))?;
+        Ok(encoded.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, Rgba, RgbaImage};
+
+    fn animation() -> Animation {
+        let frames = [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 128])]
+            .into_iter()
+            .map(|color| Frame::from_parts(RgbaImage::from_pixel(4, 4, color), 0, 0, Delay::from_numer_denom_ms(100, 1)))
+            .collect();
+        Animation { frames, loop_count: LoopCount::Infinite }
+    }
+
+    #[test]
+    fn webp_keeps_frames_and_alpha() {
+        let bytes = animation().encode_webp(None).unwrap();
+        let decoded = Animation::decode(&bytes, &AnimationConfig::default()).unwrap().unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        assert_eq!(decoded.frames[1].buffer().get_pixel(0, 0), &Rgba([0, 0, 255, 128]));
+        assert!(matches!(decoded.loop_count, LoopCount::Infinite));
+    }
+
+    #[test]
+    fn frames_are_mapped_in_order() {
+        let mapped = animation()
+            .map_frames(|frame| Ok(DynamicImage::from(frame.to_rgba8()).fliph()))
+            .unwrap();
+        assert_eq!(mapped.frames[0].buffer().get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(mapped.frames[1].buffer().get_pixel(0, 0), &Rgba([0, 0, 255, 128]));
+        assert_eq!(mapped.frames[1].delay(), Delay::from_numer_denom_ms(100, 1));
+    }
+}
@@ -0,0 +1,151 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::color_space::Oklab;
+use crate::utils::colors::RgbColor;
+use crate::utils::palette::Palette;
+
+/// What the image is put on top of when a background is set
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackgroundMode {
+    /// the chosen color
+    #[default]
+    Color,
+    /// the contrast colors of the palette from light at the top to dark at the bottom
+    LinearGradient,
+    /// the contrast colors of the palette from light in the center to dark at the edges
+    RadialGradient,
+    /// the original image, blurred and darkened
+    Blur,
+    /// a checkerboard of the two darkest contrast colors of the palette
+    Pattern,
+    /// an uploaded image which covers the whole background
+    Image,
+}
+
+/// Longest side of the blurred backdrop. Blurring a small copy is fast and the blur hides the upscaling.
+const BLUR_SIZE: u32 = 256;
+const BLUR_SIGMA: f32 = 8.;
+/// Brightness of the blurred backdrop, so that the subject stands out
+const BLUR_DARKEN: f32 = 0.6;
+/// Checkerboard cells along the shorter side of the image
+const PATTERN_CELLS: u32 = 12;
+
+impl BackgroundMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(BackgroundMode::Color),
+            1 => Some(BackgroundMode::LinearGradient),
+            2 => Some(BackgroundMode::RadialGradient),
+            3 => Some(BackgroundMode::Blur),
+            4 => Some(BackgroundMode::Pattern),
+            5 => Some(BackgroundMode::Image),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackgroundMode::Color => "Color",
+            BackgroundMode::LinearGradient => "Gradient",
+            BackgroundMode::RadialGradient => "Radial",
+            BackgroundMode::Blur => "Blur",
+            BackgroundMode::Pattern => "Pattern",
+            BackgroundMode::Image => "Image",
+        }
+    }
+
+    /// The mode after this one. `None` after the last one, which turns the background off.
+    pub fn next(&self) -> Option<Self> {
+        BackgroundMode::from_u8(*self as u8 + 1)
+    }
+}
+
+/// A small, blurred and darkened copy of the image. Transparent pixels keep their color after
+/// erasing, so the erased background shows up in the blur as well.
+pub fn blur_backdrop(image: &DynamicImage) -> DynamicImage {
+    let mut small = image.thumbnail(BLUR_SIZE, BLUR_SIZE).to_rgba8();
+    for pixel in small.pixels_mut() {
+        pixel[3] = 255;
+    }
+    let mut blurred = imageproc::filter::gaussian_blur_f32(&small, BLUR_SIGMA);
+    for pixel in blurred.pixels_mut() {
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel = (*channel as f32 * BLUR_DARKEN).round() as u8;
+        }
+    }
+    DynamicImage::from(blurred)
+}
+
+/// Renders the background of the given size.
+/// `backdrop` is the image behind the subject in `Blur` and `Image` mode, which is scaled and cropped to cover.
+/// Without a backdrop, both fall back to the color.
+pub fn render_background(
+    mode: BackgroundMode,
+    width: u32,
+    height: u32,
+    color: RgbColor,
+    palette: &Palette,
+    backdrop: Option<&DynamicImage>,
+) -> RgbaImage {
+    // dark to light
+    let mut stops = palette.contrast.clone();
+    stops.sort_by(|a, b| a.brightness().total_cmp(&b.brightness()));
+    let stops: Vec<Oklab> = stops.iter().map(Oklab::from_rgb).collect();
+    match (mode, backdrop) {
+        (BackgroundMode::LinearGradient, _) => {
+            let lut = gradient(&stops, height);
+            RgbaImage::from_fn(width, height, |_, y| lut[(height - 1 - y) as usize])
+        },
+        (BackgroundMode::RadialGradient, _) => {
+            let (center_x, center_y) = (width as f32 / 2., height as f32 / 2.);
+            let radius = center_x.hypot(center_y).max(1.);
+            let steps = radius.ceil() as u32 + 1;
+            let lut = gradient(&stops, steps);
+            RgbaImage::from_fn(width, height, |x, y| {
+                let distance = (x as f32 + 0.5 - center_x).hypot(y as f32 + 0.5 - center_y);
+                lut[steps as usize - 1 - (distance.round() as usize).min(steps as usize - 1)]
+            })
+        },
+        (BackgroundMode::Pattern, _) => {
+            let dark = stops.first().map_or(color, |stop| stop.to_rgb());
+            let light = stops.get(1).map_or(color, |stop| stop.to_rgb());
+            let cell = (width.min(height) / PATTERN_CELLS).max(1);
+            RgbaImage::from_fn(width, height, |x, y| {
+                if (x / cell + y / cell).is_multiple_of(2) { dark.to_rgba() } else { light.to_rgba() }
+            })
+        },
+        (BackgroundMode::Blur | BackgroundMode::Image, Some(backdrop)) => {
+            backdrop.resize_to_fill(width, height, FilterType::Triangle).to_rgba8()
+        },
+        (BackgroundMode::Color | BackgroundMode::Blur | BackgroundMode::Image, _) => {
+            RgbaImage::from_pixel(width, height, color.to_rgba())
+        },
+    }
+}
+
+/// `steps` colors running evenly through the stops in OKLab, which avoids muddy midpoints
+fn gradient(stops: &[Oklab], steps: u32) -> Vec<Rgba<u8>> {
+    if stops.is_empty() {
+        return vec![Rgba([0, 0, 0, 255]); steps as usize];
+    }
+    let last = steps.max(2) - 1;
+    (0..steps)
+        .map(|step| {
+            let position = step as f32 / last as f32 * (stops.len() - 1) as f32;
+            let index = (position.floor() as usize).min(stops.len() - 1);
+            let (from, next) = (&stops[index], stops.get(index + 1).unwrap_or(&stops[index]));
+            let t = position - index as f32;
+            Oklab {
+                l: from.l + (next.l - from.l) * t,
+                a: from.a + (next.a - from.a) * t,
+                b: from.b + (next.b - from.b) * t,
+            }
+            .to_rgb()
+            .to_rgba()
+        })
+        .collect()
+}
+
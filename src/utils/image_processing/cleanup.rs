@@ -0,0 +1,185 @@
+use image::{GrayImage, Luma, RgbaImage};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use rayon::prelude::*;
+
+/// Optional steps which clean up a mask after segmentation or after erasing a color.
+/// Every step is disabled by its zero value.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct MaskCleanup {
+    /// foreground islands smaller than this share of the image are removed, the biggest one is always kept
+    pub min_island: f32,
+    /// holes in the foreground smaller than this share of the image are filled
+    pub max_hole: f32,
+    /// grows (positive) or shrinks (negative) the mask by this many pixels
+    pub grow: i8,
+    /// sigma of the gaussian blur in pixels which softens the edge of the mask
+    pub feather: f32,
+}
+
+impl MaskCleanup {
+    pub fn is_enabled(&self) -> bool {
+        *self != MaskCleanup::default()
+    }
+
+    /// Runs the enabled steps in the order islands, holes, grow/shrink, feather
+    pub fn apply(&self, mask: &mut GrayImage) {
+        if self.min_island > 0. {
+            remove_islands(mask, self.min_island);
+        }
+        if self.max_hole > 0. {
+            fill_holes(mask, self.max_hole);
+        }
+        if self.grow != 0 {
+            *mask = grow_mask(mask, self.grow);
+        }
+        if self.feather > 0. {
+            *mask = gaussian_blur_f32(mask, self.feather);
+        }
+    }
+
+    /// Cleans up the alpha channel of an image, e.g. after the dominant color was erased
+    pub fn apply_to_alpha(&self, image: &mut RgbaImage) {
+        let mut alpha = GrayImage::from_fn(image.width(), image.height(), |x, y| Luma([image.get_pixel(x, y)[3]]));
+        self.apply(&mut alpha);
+        for (pixel, value) in image.pixels_mut().zip(alpha.pixels()) {
+            pixel[3] = value[0];
+        }
+    }
+}
+
+/// Pixels with at least half coverage count as foreground when looking for islands and holes
+fn binarize(mask: &GrayImage, foreground: bool) -> GrayImage {
+    GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([if (mask.get_pixel(x, y)[0] >= 128) == foreground { 255 } else { 0 }])
+    })
+}
+
+/// Size of every label, index 0 is the unlabelled area
+fn label_areas(labels: &image::ImageBuffer<Luma<u32>, Vec<u32>>) -> Vec<usize> {
+    let count = labels.pixels().map(|label| label[0]).max().unwrap_or(0) as usize + 1;
+    let mut areas = vec![0usize; count];
+    for label in labels.pixels() {
+        areas[label[0] as usize] += 1;
+    }
+    areas
+}
+
+/// Removes foreground regions smaller than `min_area` (share of the image) except for the biggest one
+pub fn remove_islands(mask: &mut GrayImage, min_area: f32) {
+    let labels = connected_components(&binarize(mask, true), Connectivity::Eight, Luma([0u8]));
+    let areas = label_areas(&labels);
+    let biggest = (1..areas.len()).max_by_key(|&label| areas[label]);
+    let min_pixels = (min_area * (mask.width() * mask.height()) as f32) as usize;
+    for (pixel, label) in mask.pixels_mut().zip(labels.pixels()) {
+        let label = label[0] as usize;
+        if label != 0 && Some(label) != biggest && areas[label] < min_pixels {
+            pixel[0] = 0;
+        }
+    }
+}
+
+/// Fills background regions smaller than `max_area` (share of the image) which don't touch the border
+pub fn fill_holes(mask: &mut GrayImage, max_area: f32) {
+    let (width, height) = mask.dimensions();
+    let labels = connected_components(&binarize(mask, false), Connectivity::Four, Luma([0u8]));
+    let mut areas = label_areas(&labels);
+    // regions at the border are the actual background
+    for (x, y, label) in labels.enumerate_pixels() {
+        if x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+            areas[label[0] as usize] = usize::MAX;
+        }
+    }
+    let max_pixels = (max_area * (width * height) as f32) as usize;
+    for (pixel, label) in mask.pixels_mut().zip(labels.pixels()) {
+        let label = label[0] as usize;
+        if label != 0 && areas[label] < max_pixels {
+            pixel[0] = 255;
+        }
+    }
+}
+
+/// Grayscale dilation (positive `pixels`) or erosion (negative) with a square,
+/// done as a horizontal and a vertical pass
+pub fn grow_mask(mask: &GrayImage, pixels: i8) -> GrayImage {
+    let radius = pixels.unsigned_abs() as usize;
+    let pick: fn(u8, u8) -> u8 = if pixels > 0 { u8::max } else { u8::min };
+    let (width, height) = (mask.width() as usize, mask.height() as usize);
+    if width == 0 || height == 0 {
+        return mask.clone();
+    }
+
+    let mut horizontal = vec![0u8; width * height];
+    horizontal
+        .par_chunks_mut(width)
+        .zip(mask.as_raw().par_chunks(width))
+        .for_each(|(out, row)| {
+            for (x, value) in out.iter_mut().enumerate() {
+                let window = &row[x.saturating_sub(radius)..(x + radius + 1).min(width)];
+                *value = window.iter().copied().reduce(pick).unwrap();
+            }
+        });
+
+    let mut result = vec![0u8; width * height];
+    result.par_chunks_mut(width).enumerate().for_each(|(y, out)| {
+        out.copy_from_slice(&horizontal[y * width..(y + 1) * width]);
+        for row in horizontal
+            .chunks(width)
+            .take((y + radius + 1).min(height))
+            .skip(y.saturating_sub(radius))
+        {
+            out.iter_mut().zip(row).for_each(|(value, &other)| *value = pick(*value, other));
+        }
+    });
+    GrayImage::from_raw(mask.width(), mask.height(), result).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 10x10 square of foreground in a 20x20 mask
+    fn square() -> GrayImage {
+        GrayImage::from_fn(20, 20, |x, y| Luma([if (5..15).contains(&x) && (5..15).contains(&y) { 255 } else { 0 }]))
+    }
+
+    #[test]
+    fn small_islands_are_removed() {
+        let mut mask = square();
+        mask.put_pixel(1, 1, Luma([255]));
+        remove_islands(&mut mask, 0.01);
+        assert_eq!(mask, square());
+    }
+
+    #[test]
+    fn the_biggest_island_is_kept() {
+        let mut mask = square();
+        remove_islands(&mut mask, 0.9);
+        assert_eq!(mask, square());
+    }
+
+    #[test]
+    fn small_holes_are_filled() {
+        let mut mask = square();
+        mask.put_pixel(9, 9, Luma([0]));
+        fill_holes(&mut mask, 0.01);
+        assert_eq!(mask, square());
+    }
+
+    #[test]
+    fn background_at_the_border_is_not_a_hole() {
+        let mut mask = square();
+        fill_holes(&mut mask, 1.);
+        assert_eq!(mask, square());
+    }
+
+    #[test]
+    fn growing_and_shrinking_move_the_edge() {
+        let grown = grow_mask(&square(), 2);
+        assert_eq!(grown.get_pixel(3, 3)[0], 255);
+        assert_eq!(grown.get_pixel(2, 9)[0], 0);
+        let shrunk = grow_mask(&square(), -2);
+        assert_eq!(shrunk.get_pixel(6, 9)[0], 0);
+        assert_eq!(shrunk.get_pixel(7, 9)[0], 255);
+    }
+}
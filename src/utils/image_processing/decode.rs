@@ -0,0 +1,24 @@
+use anyhow::Result;
+use image::{DynamicImage, ImageReader, Limits};
+use std::io::Cursor;
+
+/// Widest and highest canvas a decoder accepts
+const MAX_DIMENSION: u32 = 16384;
+/// Most memory a decoder may allocate for a single image or frame
+const MAX_ALLOC: u64 = 512 * 1024 * 1024;
+
+/// Limits for every decoder, so that a small file with a huge canvas is rejected before its pixels are allocated
+pub fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    limits
+}
+
+/// Decodes an image within the `decode_limits`. Animations give their first frame.
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(decode_limits());
+    Ok(reader.decode()?)
+}
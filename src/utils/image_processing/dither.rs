@@ -0,0 +1,208 @@
+use image::RgbaImage;
+
+use crate::utils::colors::RgbColor;
+use crate::utils::image_processing::par_rows_mut;
+
+/// How the error of mapping a pixel to a palette color is spread, to avoid banding.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum DitherMode {
+    #[default]
+    None,
+    FloydSteinberg,
+    Atkinson,
+    /// Ordered dithering with a 4x4 Bayer matrix
+    Bayer,
+}
+
+/// (dx, dy, weight) of the neighbours which receive the quantization error
+const FLOYD_STEINBERG: [(isize, usize, f32); 4] = [
+    (1, 0, 7. / 16.), (-1, 1, 3. / 16.), (0, 1, 5. / 16.), (1, 1, 1. / 16.),
+];
+// only 6/8 of the error is passed on, which keeps more contrast
+const ATKINSON: [(isize, usize, f32); 6] = [
+    (1, 0, 1. / 8.), (2, 0, 1. / 8.), (-1, 1, 1. / 8.), (0, 1, 1. / 8.), (1, 1, 1. / 8.), (0, 2, 1. / 8.),
+];
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [0., 8., 2., 10.],
+    [12., 4., 14., 6.],
+    [3., 11., 1., 9.],
+    [15., 7., 13., 5.],
+];
+/// How far (in 0-255 units) the Bayer threshold shifts a pixel in both directions
+const BAYER_SPREAD: f32 = 32.;
+/// Largest error (in 0-255 units) a pixel carries, so that a palette which can't reach
+/// a part of the image doesn't smear its error across it
+const MAX_ERROR: f32 = 128.;
+
+impl DitherMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DitherMode::None),
+            1 => Some(DitherMode::FloydSteinberg),
+            2 => Some(DitherMode::Atkinson),
+            3 => Some(DitherMode::Bayer),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            DitherMode::None => "None",
+            DitherMode::FloydSteinberg => "Floyd–Steinberg",
+            DitherMode::Atkinson => "Atkinson",
+            DitherMode::Bayer => "Bayer",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        DitherMode::from_u8((*self as u8 + 1) % 4).unwrap()
+    }
+}
+
+fn to_color(value: [f32; 3]) -> RgbColor {
+    RgbColor {
+        r: value[0].round().clamp(0., 255.) as u8,
+        g: value[1].round().clamp(0., 255.) as u8,
+        b: value[2].round().clamp(0., 255.) as u8,
+    }
+}
+
+/// Replaces every pixel with `blend(pixel, snap(pixel))`, dithering as requested. Alpha is kept.
+/// `snap` picks the palette color of a pixel, `blend` mixes it into the original pixel.
+/// Dithering only shifts what `snap` sees and only diffuses the distance to the snapped
+/// color, so that adjustments done by `blend` don't count as error.
+/// Without dithering and with Bayer, rows are processed in parallel. Error diffusion
+/// depends on the pixels before it, so it runs on one thread.
+pub fn dither(
+    image: &mut RgbaImage,
+    mode: DitherMode,
+    snap: impl Fn(RgbColor) -> RgbColor + Sync + Send,
+    blend: impl Fn(RgbColor, RgbColor) -> RgbColor + Sync + Send,
+) {
+    match mode {
+        DitherMode::None => par_rows_mut(image, |_, row| {
+            for pixel in row.chunks_exact_mut(4) {
+                let original = RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] };
+                let color = blend(original, snap(original));
+                pixel[..3].copy_from_slice(&[color.r, color.g, color.b]);
+            }
+        }),
+        DitherMode::Bayer => par_rows_mut(image, |y, row| {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let threshold = (BAYER_4X4[y as usize % 4][x % 4] + 0.5) / 16. - 0.5;
+                let offset = threshold * BAYER_SPREAD;
+                let shifted = to_color([pixel[0] as f32 + offset, pixel[1] as f32 + offset, pixel[2] as f32 + offset]);
+                let color = blend(RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] }, snap(shifted));
+                pixel[..3].copy_from_slice(&[color.r, color.g, color.b]);
+            }
+        }),
+        DitherMode::FloydSteinberg => diffuse_error(image, &FLOYD_STEINBERG, snap, blend),
+        DitherMode::Atkinson => diffuse_error(image, &ATKINSON, snap, blend),
+    }
+}
+
+fn diffuse_error(
+    image: &mut RgbaImage,
+    kernel: &[(isize, usize, f32)],
+    snap: impl Fn(RgbColor) -> RgbColor,
+    blend: impl Fn(RgbColor, RgbColor) -> RgbColor,
+) {
+    let width = image.width() as usize;
+    let rows = kernel.iter().map(|&(_, dy, _)| dy).max().unwrap_or(0) + 1;
+    // errors of the current row and the rows below, rotated after every row
+    let mut errors = vec![vec![[0f32; 3]; width]; rows];
+
+    for y in 0..image.height() {
+        for x in 0..width {
+            let pixel = image.get_pixel_mut(x as u32, y);
+            let error = errors[0][x];
+            let original = RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] };
+            let wanted = to_color([
+                pixel[0] as f32 + error[0],
+                pixel[1] as f32 + error[1],
+                pixel[2] as f32 + error[2],
+            ]);
+            let snapped = snap(wanted);
+            let color = blend(original, snapped);
+            pixel[0] = color.r;
+            pixel[1] = color.g;
+            pixel[2] = color.b;
+            let residual = [
+                wanted.r as f32 - snapped.r as f32,
+                wanted.g as f32 - snapped.g as f32,
+                wanted.b as f32 - snapped.b as f32,
+            ];
+            for &(dx, dy, weight) in kernel {
+                let nx = x as isize + dx;
+                if nx < 0 || nx >= width as isize {
+                    continue;
+                }
+                let target = &mut errors[dy][nx as usize];
+                for channel in 0..3 {
+                    target[channel] = (target[channel] + residual[channel] * weight).clamp(-MAX_ERROR, MAX_ERROR);
+                }
+            }
+        }
+        errors.rotate_left(1);
+        errors[rows - 1].fill([0.; 3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const MODES: [DitherMode; 4] = [DitherMode::None, DitherMode::FloydSteinberg, DitherMode::Atkinson, DitherMode::Bayer];
+
+    fn snapped(_: RgbColor, snapped: RgbColor) -> RgbColor {
+        snapped
+    }
+
+    fn nearest(palette: &[RgbColor]) -> impl Fn(RgbColor) -> RgbColor + Sync + Send + '_ {
+        move |color| *palette.iter().min_by(|a, b| a.color_distance(&color).total_cmp(&b.color_distance(&color))).unwrap()
+    }
+
+    fn black_and_white() -> Vec<RgbColor> {
+        vec![RgbColor { r: 0, g: 0, b: 0 }, RgbColor { r: 255, g: 255, b: 255 }]
+    }
+
+    #[test]
+    fn palette_colors_stay_unchanged() {
+        let palette = vec![RgbColor { r: 46, g: 52, b: 64 }, RgbColor { r: 236, g: 239, b: 244 }];
+        for mode in MODES {
+            let mut image = RgbaImage::from_pixel(9, 7, Rgba([46, 52, 64, 200]));
+            let original = image.clone();
+            dither(&mut image, mode, nearest(&palette), snapped);
+            assert_eq!(image, original, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn every_mode_only_writes_palette_colors() {
+        let palette = black_and_white();
+        for mode in MODES {
+            let mut image = RgbaImage::from_fn(32, 8, |x, _| Rgba([(x * 8) as u8, (x * 8) as u8, (x * 8) as u8, 255]));
+            dither(&mut image, mode, nearest(&palette), snapped);
+            for pixel in image.pixels() {
+                assert!(palette.contains(&RgbColor { r: pixel[0], g: pixel[1], b: pixel[2] }), "{:?} wrote {:?}", mode, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_of_a_gray() {
+        let palette = black_and_white();
+        for mode in [DitherMode::FloydSteinberg, DitherMode::Bayer] {
+            let mut image = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+            dither(&mut image, mode, nearest(&palette), snapped);
+            let white = image.pixels().filter(|pixel| pixel[0] == 255).count() as f32 / 256.;
+            assert!((0.4..=0.6).contains(&white), "{:?} made {} of the pixels white", mode, white);
+        }
+        // without dithering the gray becomes a single color
+        let mut image = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+        dither(&mut image, DitherMode::None, nearest(&palette), snapped);
+        assert!(image.pixels().all(|pixel| pixel == image.get_pixel(0, 0)));
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::Path;
+
+/// How a result is encoded before it's sent back.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// The format of the source, falling back to PNG for formats which can't be written
+    #[default]
+    Original,
+    Png,
+    WebpLossless,
+    /// Lossy WebP with the configured quality
+    WebpLossy,
+    /// JPEG with the configured quality. It has no alpha channel, so transparency is lost.
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(OutputFormat::Original),
+            1 => Some(OutputFormat::Png),
+            2 => Some(OutputFormat::WebpLossless),
+            3 => Some(OutputFormat::WebpLossy),
+            4 => Some(OutputFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Original => "Original",
+            OutputFormat::Png => "PNG",
+            OutputFormat::WebpLossless => "WebP Lossless",
+            OutputFormat::WebpLossy => "WebP",
+            OutputFormat::Jpeg => "JPEG",
+        }
+    }
+
+    /// Whether the quality setting has an effect on this format
+    pub fn is_lossy(&self) -> bool {
+        matches!(self, OutputFormat::WebpLossy | OutputFormat::Jpeg)
+    }
+
+    /// Replaces `Original` with the format of the source.
+    /// WebP sources stay lossless, since it's unknown how they were encoded.
+    pub fn resolve(self, source: Option<ImageFormat>) -> OutputFormat {
+        match (self, source) {
+            (OutputFormat::Original, Some(ImageFormat::Jpeg)) => OutputFormat::Jpeg,
+            (OutputFormat::Original, Some(ImageFormat::WebP)) => OutputFormat::WebpLossless,
+            (OutputFormat::Original, _) => OutputFormat::Png,
+            (format, _) => format,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Original | OutputFormat::Png => "png",
+            OutputFormat::WebpLossless | OutputFormat::WebpLossy => "webp",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+/// Encodes the image. `quality` (1-100) is used by lossy formats only.
+/// `Original` has to be resolved beforehand, otherwise PNG is written.
+pub fn encode(image: &DynamicImage, format: OutputFormat, quality: u8) -> Result<Vec<u8>> {
+    let quality = quality.clamp(1, 100);
+    let mut buffer = Vec::new();
+    match format {
+        OutputFormat::Original | OutputFormat::Png => {
+            image.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)?;
+        },
+        OutputFormat::WebpLossless => {
+            image.write_to(&mut Cursor::new(&mut buffer), ImageFormat::WebP)?;
+        },
+        OutputFormat::WebpLossy => {
+            let rgba = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(quality as f32);
+            if encoded.is_empty() {
+                bail!("Failed to encode the image as WebP");
+            }
+            buffer.extend_from_slice(&encoded);
+        },
+        OutputFormat::Jpeg => {
+            let rgb = DynamicImage::from(image.to_rgb8());
+            rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))?;
+        },
+    }
+    Ok(buffer)
+}
+
+/// The filename of a result: the stem of the source, which keeps a `SPOILER_` prefix, with a new extension
+pub fn output_filename(source: &str, extension: &str) -> String {
+    let stem = Path::new(source)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or("image");
+    format!("{}.{}", stem, extension)
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+/// Corners of an image as bits, which seed the flood fill of `EraseMode::Corners`
+pub const TOP_LEFT: u8 = 1;
+pub const TOP_RIGHT: u8 = 2;
+pub const BOTTOM_LEFT: u8 = 4;
+pub const BOTTOM_RIGHT: u8 = 8;
+pub const ALL_CORNERS: u8 = TOP_LEFT | TOP_RIGHT | BOTTOM_LEFT | BOTTOM_RIGHT;
+
+/// Which pixels close to the erased color become transparent
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum EraseMode {
+    /// every pixel in the image
+    #[default]
+    Global,
+    /// only regions connected to the border of the image
+    Border,
+    /// only regions connected to the chosen corners
+    Corners,
+}
+
+impl EraseMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(EraseMode::Global),
+            1 => Some(EraseMode::Border),
+            2 => Some(EraseMode::Corners),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EraseMode::Global => "Everywhere",
+            EraseMode::Border => "From Border",
+            EraseMode::Corners => "From Corners",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        EraseMode::from_u8((*self as u8 + 1) % 3).unwrap()
+    }
+
+    /// Pixels the flood fill starts from, nothing for `Global`
+    pub fn seeds(&self, width: u32, height: u32, corners: u8) -> Vec<(u32, u32)> {
+        if width == 0 || height == 0 {
+            return Vec::new();
+        }
+        let (right, bottom) = (width - 1, height - 1);
+        match self {
+            EraseMode::Global => Vec::new(),
+            EraseMode::Border => (0..width)
+                .flat_map(|x| [(x, 0), (x, bottom)])
+                .chain((0..height).flat_map(|y| [(0, y), (right, y)]))
+                .collect(),
+            EraseMode::Corners => [
+                (TOP_LEFT, (0, 0)),
+                (TOP_RIGHT, (right, 0)),
+                (BOTTOM_LEFT, (0, bottom)),
+                (BOTTOM_RIGHT, (right, bottom)),
+            ]
+            .into_iter()
+            .filter(|(corner, _)| corners & corner != 0)
+            .map(|(_, position)| position)
+            .collect(),
+        }
+    }
+}
+
+/// Marks every pixel which is reachable from a seed through passable pixels, moving in 4 directions.
+/// `passable` gets the index of a pixel in row-major order.
+pub fn flood_fill(width: u32, height: u32, seeds: &[(u32, u32)], passable: impl Fn(usize) -> bool) -> Vec<bool> {
+    let (width, height) = (width as usize, height as usize);
+    let mut filled = vec![false; width * height];
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for &(x, y) in seeds {
+        let index = y as usize * width + x as usize;
+        if !filled[index] && passable(index) {
+            filled[index] = true;
+            queue.push_back(index);
+        }
+    }
+    while let Some(index) = queue.pop_front() {
+        let (x, y) = (index % width, index / width);
+        let neighbours = [
+            (x > 0).then(|| index - 1),
+            (x + 1 < width).then(|| index + 1),
+            (y > 0).then(|| index - width),
+            (y + 1 < height).then(|| index + width),
+        ];
+        for neighbour in neighbours.into_iter().flatten() {
+            if !filled[neighbour] && passable(neighbour) {
+                filled[neighbour] = true;
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_fill_stops_at_an_edge() {
+        // a wall in the middle column splits the 5x5 image into two halves
+        let filled = flood_fill(5, 5, &[(0, 0)], |index| index % 5 != 2);
+        for (index, filled) in filled.into_iter().enumerate() {
+            assert_eq!(filled, index % 5 < 2, "pixel {}", index);
+        }
+    }
+
+    #[test]
+    fn flood_fill_does_not_move_diagonally() {
+        // only the diagonal from the top left to the bottom right is passable
+        let filled = flood_fill(3, 3, &[(0, 0)], |index| index % 4 == 0);
+        assert_eq!(filled.iter().filter(|&&filled| filled).count(), 1);
+    }
+
+    #[test]
+    fn seeds_of_the_modes() {
+        assert!(EraseMode::Global.seeds(4, 3, ALL_CORNERS).is_empty());
+        let border = EraseMode::Border.seeds(4, 3, 0);
+        assert!(border.iter().all(|&(x, y)| x == 0 || y == 0 || x == 3 || y == 2));
+        assert!(border.contains(&(3, 1)) && border.contains(&(2, 2)));
+        assert_eq!(EraseMode::Corners.seeds(4, 3, TOP_RIGHT | BOTTOM_LEFT), vec![(3, 0), (0, 2)]);
+    }
+}
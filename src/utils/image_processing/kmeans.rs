@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::utils::color_space::{ColorMetric, Oklab};
+use crate::utils::colors::RgbColor;
+
+/// A group of similar colors in an image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DominantColor {
+    /// average of the group
+    pub color: RgbColor,
+    /// share of the sampled pixels in [0, 1]
+    pub percentage: f64,
+    /// root mean square distance of the group to its average, in sRGB units
+    pub spread: f32,
+    /// share of the opaque border pixels which belong to the group in [0, 1], see [`measure_border`]
+    pub border: f64,
+}
+
+const ITERATIONS: usize = 12;
+
+/// Groups a histogram of colors into at most `k` dominant colors with weighted k-means in OKLab,
+/// sorted by their share. The first center is the most frequent color and every further one the color
+/// farthest from all centers, which makes the result deterministic.
+pub fn dominant_colors(histogram: &HashMap<(u8, u8, u8), u64>, k: usize) -> Vec<DominantColor> {
+    let points: Vec<(Oklab, f32)> = histogram
+        .iter()
+        .map(|(&(r, g, b), &count)| (Oklab::from_rgb(&RgbColor { r, g, b }), count as f32))
+        .collect();
+    let total: f32 = points.iter().map(|(_, weight)| weight).sum();
+    if points.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    let mut centers: Vec<Oklab> = vec![points
+        .iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+        .0];
+    while centers.len() < k.min(points.len()) {
+        let (farthest, distance) = points
+            .iter()
+            .map(|(point, _)| (*point, nearest(&centers, point).1))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if distance <= f32::EPSILON {
+            break;
+        }
+        centers.push(farthest);
+    }
+
+    let mut assignment = vec![0usize; points.len()];
+    for _ in 0..ITERATIONS {
+        let mut changed = false;
+        for (index, (point, _)) in points.iter().enumerate() {
+            let (center, _) = nearest(&centers, point);
+            changed |= assignment[index] != center;
+            assignment[index] = center;
+        }
+        // move every center to the weighted mean of its colors
+        let mut sums = vec![(0f32, 0f32, 0f32, 0f32); centers.len()];
+        for ((point, weight), &center) in points.iter().zip(&assignment) {
+            let sum = &mut sums[center];
+            sum.0 += point.l * weight;
+            sum.1 += point.a * weight;
+            sum.2 += point.b * weight;
+            sum.3 += weight;
+        }
+        for (center, (l, a, b, weight)) in centers.iter_mut().zip(sums) {
+            if weight > 0. {
+                *center = Oklab { l: l / weight, a: a / weight, b: b / weight };
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut weights = vec![0f32; centers.len()];
+    let mut squared_distances = vec![0f32; centers.len()];
+    for ((point, weight), &center) in points.iter().zip(&assignment) {
+        weights[center] += weight;
+        squared_distances[center] += point.delta_e(&centers[center]).powi(2) * weight;
+    }
+    let to_rgb_units = ColorMetric::OkLab.scale_rgb_distance(1.);
+    let mut colors: Vec<DominantColor> = centers
+        .iter()
+        .zip(weights.iter().zip(&squared_distances))
+        .filter(|(_, (&weight, _))| weight > 0.)
+        .map(|(center, (&weight, &squared))| DominantColor {
+            color: center.to_rgb(),
+            percentage: (weight / total) as f64,
+            spread: (squared / weight).sqrt() / to_rgb_units,
+            border: 0.,
+        })
+        .collect();
+    colors.sort_by(|a, b| b.percentage.total_cmp(&a.percentage));
+    colors
+}
+
+/// Sets the `border` share of every dominant color by assigning each border pixel to the nearest of them.
+/// Backgrounds touch the border, while the colors of a centered subject mostly don't.
+pub fn measure_border(colors: &mut [DominantColor], border: &[RgbColor]) {
+    if colors.is_empty() || border.is_empty() {
+        return;
+    }
+    let centers: Vec<Oklab> = colors.iter().map(|dominant| Oklab::from_rgb(&dominant.color)).collect();
+    let mut counts = vec![0usize; centers.len()];
+    for pixel in border {
+        counts[nearest(&centers, &Oklab::from_rgb(pixel)).0] += 1;
+    }
+    for (dominant, count) in colors.iter_mut().zip(counts) {
+        dominant.border = count as f64 / border.len() as f64;
+    }
+}
+
+/// Index of the nearest center and its distance
+fn nearest(centers: &[Oklab], point: &Oklab) -> (usize, f32) {
+    centers
+        .iter()
+        .map(|center| center.delta_e(point))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAVY: RgbColor = RgbColor { r: 46, g: 52, b: 64 };
+    const FROST: RgbColor = RgbColor { r: 136, g: 192, b: 208 };
+
+    #[test]
+    fn two_colors_are_both_found() {
+        let histogram = HashMap::from([((46, 52, 64), 300), ((136, 192, 208), 100)]);
+        let colors = dominant_colors(&histogram, 4);
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].color, NAVY);
+        assert_eq!(colors[1].color, FROST);
+        assert_eq!(colors[0].percentage, 0.75);
+        assert!(colors.iter().all(|dominant| dominant.spread < 1.));
+    }
+
+    #[test]
+    fn similar_colors_are_grouped() {
+        let histogram = HashMap::from([((46, 52, 64), 10), ((48, 52, 64), 10), ((136, 192, 208), 10), ((138, 192, 208), 10)]);
+        let colors = dominant_colors(&histogram, 2);
+        assert_eq!(colors.len(), 2);
+        assert!(colors.iter().all(|dominant| dominant.percentage == 0.5));
+        assert!(colors.iter().any(|dominant| dominant.color.color_distance(&NAVY) < 2.));
+        assert!(colors.iter().any(|dominant| dominant.color.color_distance(&FROST) < 2.));
+    }
+
+    #[test]
+    fn border_share_counts_the_nearest_color() {
+        let histogram = HashMap::from([((46, 52, 64), 300), ((136, 192, 208), 100)]);
+        let mut colors = dominant_colors(&histogram, 2);
+        measure_border(&mut colors, &[NAVY, NAVY, NAVY, RgbColor { r: 130, g: 190, b: 200 }]);
+        assert_eq!(colors[0].border, 0.75);
+        assert_eq!(colors[1].border, 0.25);
+    }
+}
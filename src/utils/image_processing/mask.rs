@@ -0,0 +1,324 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, Luma, RgbaImage};
+use rayon::prelude::*;
+
+use super::cleanup::grow_mask;
+
+/// How the low resolution mask of a model is brought to the size of the image
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum MaskRefinement {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    /// Bicubic, then a guided filter snaps the edges to the image and edge colors are decontaminated
+    #[default]
+    Refined,
+}
+
+impl MaskRefinement {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MaskRefinement::Nearest),
+            1 => Some(MaskRefinement::Bilinear),
+            2 => Some(MaskRefinement::Bicubic),
+            3 => Some(MaskRefinement::Refined),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaskRefinement::Nearest => "Nearest",
+            MaskRefinement::Bilinear => "Bilinear",
+            MaskRefinement::Bicubic => "Bicubic",
+            MaskRefinement::Refined => "Refined",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        MaskRefinement::from_u8((*self as u8 + 1) % 4).unwrap()
+    }
+
+    fn filter(&self) -> FilterType {
+        match self {
+            MaskRefinement::Nearest => FilterType::Nearest,
+            MaskRefinement::Bilinear => FilterType::Triangle,
+            MaskRefinement::Bicubic | MaskRefinement::Refined => FilterType::CatmullRom,
+        }
+    }
+}
+
+/// Regularization of the guided filter. Smaller values follow the edges of the image more closely.
+const GUIDED_FILTER_EPSILON: f32 = 1e-3;
+
+/// Scales the mask to the size of `image` and refines its edges as requested
+pub fn upsample_mask(mask: &GrayImage, image: &RgbaImage, refinement: MaskRefinement) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let upsampled = DynamicImage::ImageLuma8(mask.clone())
+        .resize_exact(width, height, refinement.filter())
+        .to_luma8();
+    if refinement != MaskRefinement::Refined {
+        return upsampled;
+    }
+    guided_filter(image, &upsampled, refinement_radius(mask, image), GUIDED_FILTER_EPSILON)
+}
+
+/// About two mask pixels in image pixels, so that the filter can move an edge by the blockiness of the mask
+pub fn refinement_radius(mask: &GrayImage, image: &RgbaImage) -> usize {
+    let scale = image.width().max(image.height()) as f32 / mask.width().max(mask.height()).max(1) as f32;
+    (scale * 2.).ceil().clamp(2., 32.) as usize
+}
+
+/// Mean of every pixel over a (2 * radius + 1)² window, clipped at the borders
+pub(super) fn box_mean(values: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+    // horizontal sums, row by row in parallel
+    let mut horizontal = vec![0f32; values.len()];
+    horizontal
+        .par_chunks_mut(width)
+        .zip(values.par_chunks(width))
+        .for_each(|(out, row)| {
+            let mut prefix = vec![0f32; width + 1];
+            for x in 0..width {
+                prefix[x + 1] = prefix[x] + row[x];
+            }
+            for (x, sum) in out.iter_mut().enumerate() {
+                let (start, end) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+                *sum = prefix[end] - prefix[start];
+            }
+        });
+
+    // vertical sums with a running window, divided by the window area
+    let mut result = vec![0f32; values.len()];
+    let mut column_sums = vec![0f32; width];
+    for row in horizontal.chunks(width).take(radius.min(height)) {
+        column_sums.iter_mut().zip(row).for_each(|(sum, value)| *sum += value);
+    }
+    for y in 0..height {
+        if y + radius < height {
+            let row = &horizontal[(y + radius) * width..(y + radius + 1) * width];
+            column_sums.iter_mut().zip(row).for_each(|(sum, value)| *sum += value);
+        }
+        if y > radius {
+            let row = &horizontal[(y - radius - 1) * width..(y - radius) * width];
+            column_sums.iter_mut().zip(row).for_each(|(sum, value)| *sum -= value);
+        }
+        let rows = ((y + radius + 1).min(height) - y.saturating_sub(radius)) as f32;
+        for x in 0..width {
+            let columns = ((x + radius + 1).min(width) - x.saturating_sub(radius)) as f32;
+            result[y * width + x] = column_sums[x] / (rows * columns);
+        }
+    }
+    result
+}
+
+fn luminance(image: &RgbaImage) -> Vec<f32> {
+    image
+        .pixels()
+        .map(|pixel| (0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32) / 255.)
+        .collect()
+}
+
+/// Edge-aware smoothing of the mask with the luminance of the image as guide (He et al., "Guided Image Filtering").
+/// Mask edges move to nearby edges of the image, which removes the blocks and halos of upsampling.
+pub fn guided_filter(image: &RgbaImage, mask: &GrayImage, radius: usize, epsilon: f32) -> GrayImage {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let guide = luminance(image);
+    let input: Vec<f32> = mask.pixels().map(|pixel| pixel[0] as f32 / 255.).collect();
+
+    let mean_guide = box_mean(&guide, width, height, radius);
+    let mean_input = box_mean(&input, width, height, radius);
+    let product: Vec<f32> = guide.iter().zip(&input).map(|(g, p)| g * p).collect();
+    let squared: Vec<f32> = guide.iter().map(|g| g * g).collect();
+    let mean_product = box_mean(&product, width, height, radius);
+    let mean_squared = box_mean(&squared, width, height, radius);
+
+    let mut a = vec![0f32; guide.len()];
+    let mut b = vec![0f32; guide.len()];
+    for i in 0..guide.len() {
+        let variance = mean_squared[i] - mean_guide[i] * mean_guide[i];
+        let covariance = mean_product[i] - mean_guide[i] * mean_input[i];
+        a[i] = covariance / (variance + epsilon);
+        b[i] = mean_input[i] - a[i] * mean_guide[i];
+    }
+    let mean_a = box_mean(&a, width, height, radius);
+    let mean_b = box_mean(&b, width, height, radius);
+
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let i = y as usize * width + x as usize;
+        Luma([((mean_a[i] * guide[i] + mean_b[i]) * 255.).round().clamp(0., 255.) as u8])
+    })
+}
+
+/// Removes the background color which bled into semi-transparent edge pixels.
+/// The background color around a pixel is estimated from its transparent neighbours,
+/// then the pixel is solved for the foreground color of `color = alpha * foreground + (1 - alpha) * background`.
+pub fn decontaminate_colors(image: &mut RgbaImage, radius: usize) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let transparency: Vec<f32> = image.pixels().map(|pixel| 1. - pixel[3] as f32 / 255.).collect();
+    let mean_transparency = box_mean(&transparency, width, height, radius);
+    let backgrounds: Vec<Vec<f32>> = (0..3)
+        .map(|channel| {
+            let weighted: Vec<f32> = image
+                .pixels()
+                .zip(&transparency)
+                .map(|(pixel, t)| pixel[channel] as f32 * t)
+                .collect();
+            box_mean(&weighted, width, height, radius)
+        })
+        .collect();
+
+    for (i, pixel) in image.pixels_mut().enumerate() {
+        let alpha = pixel[3] as f32 / 255.;
+        // opaque pixels have nothing to remove, almost transparent ones would amplify noise
+        if !(0.05..0.98).contains(&alpha) || mean_transparency[i] <= f32::EPSILON {
+            continue;
+        }
+        for channel in 0..3 {
+            let background = backgrounds[channel][i] / mean_transparency[i];
+            let foreground = (pixel[channel] as f32 - (1. - alpha) * background) / alpha;
+            pixel[channel] = foreground.round().clamp(0., 255.) as u8;
+        }
+    }
+}
+
+/// How the hybrid eraser combines the mask of a model with the distance to the most present color
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum MaskFusion {
+    /// The model decides where it is certain, the color distance in a band around the edges of the model
+    #[default]
+    Trimap,
+    /// Weighted average of both
+    Blend,
+}
+
+impl MaskFusion {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MaskFusion::Trimap),
+            1 => Some(MaskFusion::Blend),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaskFusion::Trimap => "Trimap",
+            MaskFusion::Blend => "Blend",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        MaskFusion::from_u8((*self as u8 + 1) % 2).unwrap()
+    }
+}
+
+/// Combines the mask of a model with the alpha of the color eraser, both in the size of the image.
+/// `band` is the width of the unknown region of the trimap in pixels, `weight` the share of the model in the blend.
+pub fn fuse_masks(model: &GrayImage, color: &GrayImage, fusion: MaskFusion, band: usize, weight: f32) -> GrayImage {
+    match fusion {
+        MaskFusion::Trimap => {
+            let band = band.clamp(1, i8::MAX as usize) as i8;
+            // certain foreground stays foreground when shrunk, certain background stays background when grown
+            let foreground = grow_mask(model, -band);
+            let background = grow_mask(model, band);
+            GrayImage::from_fn(model.width(), model.height(), |x, y| {
+                if foreground.get_pixel(x, y)[0] >= 230 {
+                    Luma([255])
+                } else if background.get_pixel(x, y)[0] <= 25 {
+                    Luma([0])
+                } else {
+                    *color.get_pixel(x, y)
+                }
+            })
+        },
+        MaskFusion::Blend => {
+            let weight = weight.clamp(0., 1.);
+            GrayImage::from_fn(model.width(), model.height(), |x, y| {
+                let value = weight * model.get_pixel(x, y)[0] as f32 + (1. - weight) * color.get_pixel(x, y)[0] as f32;
+                Luma([value.round() as u8])
+            })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// Dark left and bright right half with the edge at x = 12
+    fn two_halves() -> RgbaImage {
+        RgbaImage::from_fn(24, 12, |x, _| if x < 12 { Rgba([20, 20, 20, 255]) } else { Rgba([230, 230, 230, 255]) })
+    }
+
+    #[test]
+    fn guided_filter_keeps_a_mask_edge_on_an_image_edge() {
+        let mask = GrayImage::from_fn(24, 12, |x, _| Luma([if x < 12 { 0 } else { 255 }]));
+        let filtered = guided_filter(&two_halves(), &mask, 4, GUIDED_FILTER_EPSILON);
+        for (x, _, pixel) in filtered.enumerate_pixels() {
+            if x < 12 {
+                assert!(pixel[0] <= 5, "{} at x = {}", pixel[0], x);
+            } else {
+                assert!(pixel[0] >= 250, "{} at x = {}", pixel[0], x);
+            }
+        }
+    }
+
+    #[test]
+    fn guided_filter_smooths_a_mask_where_the_image_is_flat() {
+        let image = RgbaImage::from_pixel(24, 12, Rgba([128, 128, 128, 255]));
+        let checkerboard = GrayImage::from_fn(24, 12, |x, y| Luma([if (x + y) % 2 == 0 { 0 } else { 255 }]));
+        let filtered = guided_filter(&image, &checkerboard, 4, GUIDED_FILTER_EPSILON);
+        assert!(filtered.pixels().all(|pixel| (100..=155).contains(&pixel[0])));
+    }
+
+    #[test]
+    fn decontamination_removes_the_background_from_half_transparent_pixels() {
+        // a red subject on a green background, with a half transparent edge where both mixed
+        let mut image = RgbaImage::from_fn(24, 12, |x, _| match x {
+            0..10 => Rgba([0, 255, 0, 0]),
+            10..12 => Rgba([128, 128, 0, 128]),
+            _ => Rgba([255, 0, 0, 255]),
+        });
+        let original = image.clone();
+        decontaminate_colors(&mut image, 3);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if (10..12).contains(&x) {
+                assert!(pixel[0] > 192 && pixel[1] < 64, "{:?} at x = {}", pixel, x);
+                assert_eq!(pixel[3], 128);
+            } else {
+                assert_eq!(pixel, original.get_pixel(x, y));
+            }
+        }
+    }
+
+    /// A 10x10 square of foreground in a 30x30 mask
+    fn model_square() -> GrayImage {
+        GrayImage::from_fn(30, 30, |x, y| Luma([if (10..20).contains(&x) && (10..20).contains(&y) { 255 } else { 0 }]))
+    }
+
+    #[test]
+    fn trimap_takes_certain_regions_from_the_model_and_the_band_from_the_colors() {
+        let color = GrayImage::from_pixel(30, 30, Luma([128]));
+        let fused = fuse_masks(&model_square(), &color, MaskFusion::Trimap, 2, 0.5);
+        // certain foreground and background
+        assert_eq!(fused.get_pixel(15, 15)[0], 255);
+        assert_eq!(fused.get_pixel(2, 2)[0], 0);
+        assert_eq!(fused.get_pixel(25, 15)[0], 0);
+        // the band around the edge of the model, on both sides
+        for x in [9, 10, 11, 18, 19, 20] {
+            assert_eq!(fused.get_pixel(x, 15)[0], 128, "x = {}", x);
+        }
+    }
+
+    #[test]
+    fn blend_weight_gives_back_either_mask() {
+        let model = model_square();
+        let color = GrayImage::from_fn(30, 30, |x, _| Luma([(x * 8) as u8]));
+        assert_eq!(fuse_masks(&model, &color, MaskFusion::Blend, 2, 1.), model);
+        assert_eq!(fuse_masks(&model, &color, MaskFusion::Blend, 2, 0.), color);
+        let half = fuse_masks(&model, &color, MaskFusion::Blend, 2, 0.5);
+        assert_eq!(half.get_pixel(15, 15)[0], ((255. + 120.) / 2f32).round() as u8);
+    }
+}
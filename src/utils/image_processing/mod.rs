@@ -1,2 +1,28 @@
 pub mod tp_image;
-pub use tp_image::generate_tp_image;
\ No newline at end of file
+pub mod pipeline;
+pub mod dither;
+pub mod parallel;
+pub mod animation;
+pub mod decode;
+pub mod encode;
+pub mod preprocess;
+pub mod mask;
+pub mod tiling;
+pub mod cleanup;
+pub mod flood;
+pub mod kmeans;
+pub mod background;
+pub use tp_image::generate_tp_image;
+pub use pipeline::{FilterContext, FilterStep, ImageFilter, Pipeline};
+pub use dither::{dither, DitherMode};
+pub use parallel::{par_pixels_mut, par_rows_mut, ColorCache};
+pub use animation::Animation;
+pub use decode::{decode_image, decode_limits};
+pub use encode::{encode, output_filename, OutputFormat};
+pub use preprocess::{crop_mask, preprocess, Placement, ResizeMode};
+pub use mask::{decontaminate_colors, fuse_masks, refinement_radius, upsample_mask, MaskFusion, MaskRefinement};
+pub use tiling::{plan_tiles, Tile, TileBlender};
+pub use cleanup::MaskCleanup;
+pub use flood::{flood_fill, EraseMode};
+pub use kmeans::{dominant_colors, measure_border, DominantColor};
+pub use background::{blur_backdrop, render_background, BackgroundMode};
@@ -0,0 +1,60 @@
+use dashmap::DashMap;
+use image::{Pixel, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::utils::colors::RgbColor;
+
+/// Calls `f` with the row index and the raw RGBA bytes of every row, spreading the rows across all cores.
+pub fn par_rows_mut<F>(image: &mut RgbaImage, f: F)
+where
+    F: Fn(u32, &mut [u8]) + Sync + Send,
+{
+    let row_length = image.width() as usize * 4;
+    if row_length == 0 {
+        return;
+    }
+    image
+        .par_chunks_exact_mut(row_length)
+        .enumerate()
+        .for_each(|(y, row)| f(y as u32, row));
+}
+
+/// Calls `f` for every pixel, spreading the rows across all cores.
+pub fn par_pixels_mut<F>(image: &mut RgbaImage, f: F)
+where
+    F: Fn(&mut Rgba<u8>) + Sync + Send,
+{
+    par_rows_mut(image, |_, row| {
+        row.chunks_exact_mut(4).for_each(|pixel| f(Rgba::from_slice_mut(pixel)));
+    });
+}
+
+/// Remembers the result of a per-color computation. It is shared by all threads of a filter,
+/// so that every distinct color is only computed about once per image.
+pub struct ColorCache<V> {
+    map: DashMap<(u8, u8, u8), V>,
+}
+
+impl<V: Copy> ColorCache<V> {
+    pub fn new() -> Self {
+        ColorCache { map: DashMap::new() }
+    }
+
+    /// Returns the cached value of `color` or computes and stores it.
+    /// The computation runs without holding a lock, so two threads may compute the same color.
+    pub fn get_or_insert_with(&self, color: RgbColor, compute: impl FnOnce() -> V) -> V {
+        let key = (color.r, color.g, color.b);
+        if let Some(value) = self.map.get(&key) {
+            return *value;
+        }
+        let value = compute();
+        self.map.insert(key, value);
+        value
+    }
+}
+
+impl<V: Copy> Default for ColorCache<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::Result;
+use log::debug;
+use image::imageops::overlay;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+use super::background::{blur_backdrop, render_background, BackgroundMode};
+use crate::utils::colors::{
+    apply_hue_rotate, apply_invert, apply_perceptual_hue_rotate, apply_nord_filter, apply_sepia, apply_smart_invert, erase_background,
+    ImageInformation, NordOptions, RgbColor, SMART_INVERT_SATURATION_GATE,
+};
+use crate::utils::model_manager::ModelManager;
+use crate::utils::palette::Palette;
+
+
+/// Everything a filter may look at besides the image it transforms.
+pub struct FilterContext<'a> {
+    pub options: &'a NordOptions,
+    pub info: &'a ImageInformation,
+    pub palette: &'a Palette,
+    pub models: &'a ModelManager,
+    /// image behind the subject in `BackgroundMode::Blur` and `BackgroundMode::Image`
+    pub backdrop: Option<&'a DynamicImage>,
+    /// SHA-256 of the source file, as long as no filter changed the image yet
+    pub source_hash: Option<&'a str>,
+}
+
+/// One step of a [`Pipeline`]. Filters take the image by value and hand back the
+/// transformed one, so steps can be ordered and repeated freely.
+pub trait ImageFilter: Debug + Send + Sync {
+    fn name(&self) -> &str;
+    fn apply(&self, image: DynamicImage, context: &FilterContext) -> Result<DynamicImage>;
+}
+
+/// The built-in filters. They can be deserialized, which is what makes presets
+/// definable in `config.toml`:
+///
+/// ```toml
+/// [presets]
+/// nord = [{ filter = "sepia" }, { filter = "invert" }, { filter = "hue_rotate", degrees = 180.0 }, { filter = "nord" }]
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "filter", rename_all = "snake_case")]
+pub enum FilterStep {
+    /// Removes the background with the model selected in the options
+    EraseBackground,
+    Invert,
+    /// Inverts the lightness only. Pixels more colorful than `saturation_gate` (OKLab chroma) are kept.
+    SmartInvert { saturation_gate: Option<f32> },
+    Sepia,
+    /// Turns the hue in RGB like the presets always did, or with `perceptual` in OKLab, which keeps
+    /// the lightness and chroma of every color
+    HueRotate {
+        degrees: f32,
+        #[serde(default)]
+        perceptual: bool,
+    },
+    /// Snaps colors towards the selected palette
+    Nord,
+    /// Puts the image on top of a solid color, a gradient or pattern of the palette, a blurred copy
+    /// of the image or an uploaded image
+    Background {
+        color: RgbColor,
+        #[serde(default)]
+        mode: BackgroundMode,
+    },
+}
+
+impl ImageFilter for FilterStep {
+    fn name(&self) -> &str {
+        match self {
+            FilterStep::EraseBackground => "erase_background",
+            FilterStep::Invert => "invert",
+            FilterStep::SmartInvert { .. } => "smart_invert",
+            FilterStep::Sepia => "sepia",
+            FilterStep::HueRotate { .. } => "hue_rotate",
+            FilterStep::Nord => "nord",
+            FilterStep::Background { .. } => "background",
+        }
+    }
+
+    fn apply(&self, mut image: DynamicImage, context: &FilterContext) -> Result<DynamicImage> {
+        let image = match self {
+            FilterStep::EraseBackground => erase_background(image, context.source_hash, context.options, context.info, context.models)?,
+            FilterStep::Invert if context.options.invert_strength >= 1. => {
+                image.invert();
+                image
+            },
+            FilterStep::Invert => {
+                let mut rgba = image.to_rgba8();
+                apply_invert(&mut rgba, context.options.invert_strength);
+                DynamicImage::from(rgba)
+            },
+            FilterStep::SmartInvert { saturation_gate } => {
+                let mut rgba = image.to_rgba8();
+                apply_smart_invert(&mut rgba, *saturation_gate, context.options.invert_strength);
+                DynamicImage::from(rgba)
+            },
+            FilterStep::Sepia => {
+                let mut rgba = image.to_rgba8();
+                apply_sepia(&mut rgba, context.options.sepia_strength);
+                DynamicImage::from(rgba)
+            },
+            FilterStep::HueRotate { degrees, perceptual } => {
+                let mut rgba = image.to_rgba8();
+                if *perceptual {
+                    apply_perceptual_hue_rotate(&mut rgba, *degrees);
+                } else {
+                    apply_hue_rotate(&mut rgba, *degrees);
+                }
+                DynamicImage::from(rgba)
+            },
+            FilterStep::Nord => {
+                let mut rgba = image.to_rgba8();
+                apply_nord_filter(&mut rgba, context.options, context.palette);
+                DynamicImage::from(rgba)
+            },
+            FilterStep::Background { color, mode } => {
+                // without a prepared backdrop, the image at this step is blurred
+                let blurred = (*mode == BackgroundMode::Blur && context.backdrop.is_none()).then(|| blur_backdrop(&image));
+                let backdrop = blurred.as_ref().or(context.backdrop);
+                let mut background = render_background(*mode, image.width(), image.height(), *color, context.palette, backdrop);
+                overlay(&mut background, &image, 0, 0);
+                DynamicImage::from(background)
+            },
+        };
+        Ok(image)
+    }
+}
+
+/// An ordered list of filters which are applied one after another.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn ImageFilter>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline { steps: Vec::new() }
+    }
+
+    /// Appends a filter to the end of the pipeline
+    pub fn then(mut self, filter: impl ImageFilter + 'static) -> Self {
+        self.steps.push(Box::new(filter));
+        self
+    }
+
+    pub fn from_steps(steps: Vec<FilterStep>) -> Self {
+        steps.into_iter().fold(Pipeline::new(), |pipeline, step| pipeline.then(step))
+    }
+
+    /// Builds the pipeline described by the toggles of `options`.
+    /// The order is: erase → invert → sepia → hue rotate → nord → background
+    pub fn from_options(options: &NordOptions) -> Self {
+        let mut steps = Vec::new();
+        if options.erase_most_present_color {
+            steps.push(FilterStep::EraseBackground);
+        }
+        if options.invert && options.smart_invert {
+            steps.push(FilterStep::SmartInvert { saturation_gate: Some(SMART_INVERT_SATURATION_GATE) });
+        } else if options.invert {
+            steps.push(FilterStep::Invert);
+        }
+        if options.sepia {
+            steps.push(FilterStep::Sepia);
+        }
+        if options.hue_rotate != 0.0 {
+            steps.push(FilterStep::HueRotate { degrees: options.hue_rotate, perceptual: false });
+        }
+        if options.nord {
+            steps.push(FilterStep::Nord);
+        }
+        if let Some(color) = options.background_color {
+            steps.push(FilterStep::Background { color, mode: options.background_mode });
+        }
+        Pipeline::from_steps(steps)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.steps.iter().map(|step| step.name()).collect()
+    }
+
+    pub fn apply(&self, image: DynamicImage, context: &FilterContext) -> Result<DynamicImage> {
+        let mut source_hash = context.source_hash;
+        self.steps.iter().try_fold(image, |image, step| {
+            let start = std::time::Instant::now();
+            let image = step.apply(image, &FilterContext { source_hash, ..*context })?;
+            // the image doesn't match the source anymore
+            source_hash = None;
+            debug!("[{}] Time taken: {:.3} seconds", step.name(), start.elapsed().as_secs_f32());
+            Ok(image)
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{bail, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage};
+use onnxruntime::ndarray::{Array4, ArrayD};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::models::{ChannelOrder, ModelConfig};
+
+/// How an image is fitted into the fixed input size of a model
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeMode {
+    /// Scales the image to fit and pads the rest, which keeps the aspect ratio
+    #[default]
+    Letterbox,
+    /// Stretches the image to the input size
+    Stretch,
+}
+
+/// Where the image was placed inside the model input, needed to map the mask back onto the image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Placement {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Placement {
+    fn new(image_size: (u32, u32), input_size: (u32, u32), mode: ResizeMode) -> Self {
+        let (input_width, input_height) = input_size;
+        match mode {
+            ResizeMode::Stretch => Placement { left: 0, top: 0, width: input_width, height: input_height },
+            ResizeMode::Letterbox => {
+                let scale = f32::min(
+                    input_width as f32 / image_size.0.max(1) as f32,
+                    input_height as f32 / image_size.1.max(1) as f32,
+                );
+                let width = ((image_size.0 as f32 * scale).round() as u32).clamp(1, input_width);
+                let height = ((image_size.1 as f32 * scale).round() as u32).clamp(1, input_height);
+                Placement {
+                    left: (input_width - width) / 2,
+                    top: (input_height - height) / 2,
+                    width,
+                    height,
+                }
+            },
+        }
+    }
+}
+
+/// Turns the image into the NCHW input tensor of `model`, normalized with its mean and std.
+/// Padding is left at 0, which is the color `model.mean` after normalization. That is the average
+/// color of the training data for models normalized with it, and black for models with a mean of 0.
+pub fn preprocess(image: &DynamicImage, model: &ModelConfig) -> (Array4<f32>, Placement) {
+    let placement = Placement::new(image.dimensions(), (model.width, model.height), model.resize);
+    let resized = image
+        .resize_exact(placement.width, placement.height, FilterType::Triangle)
+        .to_rgb8();
+    let channels = match model.channel_order {
+        ChannelOrder::Rgb => [0, 1, 2],
+        ChannelOrder::Bgr => [2, 1, 0],
+    };
+
+    let mut input_tensor = Array4::<f32>::zeros((1, 3, model.height as usize, model.width as usize));
+    for (x, y, pixel) in resized.enumerate_pixels() {
+        let (tx, ty) = ((x + placement.left) as usize, (y + placement.top) as usize);
+        for (channel, &source) in channels.iter().enumerate() {
+            let value = pixel[source] as f32 / 255.0;
+            input_tensor[[0, channel, ty, tx]] = (value - model.mean[channel]) / model.std[channel];
+        }
+    }
+    (input_tensor, placement)
+}
+
+/// Cuts the part of the model output which covers the image, still in the resolution of the model.
+/// The output may have the shape `[H, W]`, `[1, H, W]` or `[1, 1, H, W]`.
+pub fn crop_mask(mask: &ArrayD<f32>, placement: &Placement) -> Result<GrayImage> {
+    let shape = mask.shape();
+    if shape.len() < 2 || shape[..shape.len() - 2].iter().any(|&dimension| dimension != 1) {
+        bail!("Expected a single mask, got an output of shape {:?}", shape);
+    }
+    let (height, width) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+    if (placement.left + placement.width) as usize > width || (placement.top + placement.height) as usize > height {
+        bail!("The mask of size {}x{} is smaller than the model input", width, height);
+    }
+    let values: Vec<f32> = mask.iter().copied().collect();
+    Ok(GrayImage::from_fn(placement.width, placement.height, |x, y| {
+        let index = (y + placement.top) as usize * width + (x + placement.left) as usize;
+        image::Luma([(values[index] * 255.0).clamp(0.0, 255.0) as u8])
+    }))
+}
@@ -0,0 +1,178 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, Luma};
+
+use super::mask::box_mean;
+
+/// A part of the image which is segmented on its own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Start positions of tiles with the given length, spread evenly so that neighbours overlap at least by `overlap`
+fn positions(length: u32, tile: u32, overlap: f32) -> Vec<u32> {
+    if tile >= length {
+        return vec![0];
+    }
+    let stride = (tile as f32 * (1. - overlap)).max(1.);
+    let count = ((length - tile) as f32 / stride).ceil() as u64 + 1;
+    (0..count)
+        .map(|i| ((length - tile) as u64 * i / (count - 1)) as u32)
+        .collect()
+}
+
+/// Splits the image into overlapping tiles of the model input size.
+/// Tiles grow until there are at most `max_tiles` of them.
+/// Returns nothing when a single tile would cover the whole image, since tiling wouldn't add detail then.
+pub fn plan_tiles(image_size: (u32, u32), tile_size: (u32, u32), overlap: f32, max_tiles: usize) -> Vec<Tile> {
+    let (width, height) = image_size;
+    let overlap = overlap.clamp(0., 0.9);
+    let (mut tile_width, mut tile_height) = (tile_size.0.max(1), tile_size.1.max(1));
+    loop {
+        let (tile_width_clamped, tile_height_clamped) = (tile_width.min(width), tile_height.min(height));
+        let lefts = positions(width, tile_width_clamped, overlap);
+        let tops = positions(height, tile_height_clamped, overlap);
+        let count = lefts.len() * tops.len();
+        if count <= max_tiles.max(1) {
+            if count <= 1 {
+                return Vec::new();
+            }
+            return tops
+                .iter()
+                .flat_map(|&top| lefts.iter().map(move |&left| Tile {
+                    left,
+                    top,
+                    width: tile_width_clamped,
+                    height: tile_height_clamped,
+                }))
+                .collect();
+        }
+        tile_width = (tile_width as f32 * 1.1).ceil() as u32;
+        tile_height = (tile_height as f32 * 1.1).ceil() as u32;
+    }
+}
+
+/// Weight of a pixel at `position` inside a tile, fading out towards edges which overlap with other tiles
+fn edge_weight(start: u32, length: u32, position: u32, total: u32, feather: u32) -> f32 {
+    // nothing overlaps at the border of the image, so pixels there keep their full weight
+    let from_start = if start == 0 { f32::INFINITY } else { (position + 1) as f32 / feather as f32 };
+    let from_end = if start + length >= total { f32::INFINITY } else { (length - position) as f32 / feather as f32 };
+    from_start.min(from_end).min(1.)
+}
+
+/// Merges the masks of overlapping tiles into one mask of the full image
+pub struct TileBlender {
+    width: u32,
+    height: u32,
+    feather: u32,
+    sum: Vec<f32>,
+    weight: Vec<f32>,
+}
+
+impl TileBlender {
+    /// `feather` is the width in pixels over which tiles fade into their neighbours
+    pub fn new(width: u32, height: u32, feather: u32) -> Self {
+        let size = width as usize * height as usize;
+        TileBlender { width, height, feather: feather.max(1), sum: vec![0.; size], weight: vec![0.; size] }
+    }
+
+    /// Adds the mask of a tile, which has to be scaled to the size of the tile already
+    pub fn add(&mut self, tile: &Tile, mask: &GrayImage) {
+        for y in 0..tile.height.min(mask.height()) {
+            let weight_y = edge_weight(tile.top, tile.height, y, self.height, self.feather);
+            for x in 0..tile.width.min(mask.width()) {
+                let weight = weight_y * edge_weight(tile.left, tile.width, x, self.width, self.feather);
+                let index = (tile.top + y) as usize * self.width as usize + (tile.left + x) as usize;
+                self.sum[index] += weight * mask.get_pixel(x, y)[0] as f32 / 255.;
+                self.weight[index] += weight;
+            }
+        }
+    }
+
+    /// Combines the tiles with the mask of a pass over the whole image.
+    /// A tile lacks the context of the whole image and may find a subject in plain background,
+    /// so tiles only decide close to the edges of the global mask, where its low resolution loses detail.
+    pub fn finish(self, global: &GrayImage) -> GrayImage {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let global: Vec<f32> = DynamicImage::ImageLuma8(global.clone())
+            .resize_exact(self.width, self.height, FilterType::Triangle)
+            .to_luma8()
+            .pixels()
+            .map(|pixel| pixel[0] as f32 / 255.)
+            .collect();
+        let surrounding = box_mean(&global, width, height, self.feather as usize);
+        GrayImage::from_fn(self.width, self.height, |x, y| {
+            let index = y as usize * width + x as usize;
+            // 0 where the surrounding of the pixel is all foreground or all background, 1 close to an edge
+            let uncertainty = (surrounding[index].min(1. - surrounding[index]) / 0.02).clamp(0., 1.);
+            let tiled = if self.weight[index] > 0. { self.sum[index] / self.weight[index] } else { global[index] };
+            let value = global[index] + uncertainty * (tiled - global[index]);
+            Luma([(value * 255.).round().clamp(0., 255.) as u8])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn covered(tiles: &[Tile], width: u32, height: u32) -> bool {
+        (0..height).all(|y| (0..width).all(|x| {
+            tiles.iter().any(|tile| (tile.left..tile.left + tile.width).contains(&x) && (tile.top..tile.top + tile.height).contains(&y))
+        }))
+    }
+
+    #[test]
+    fn tiles_cover_the_image() {
+        for (image_size, tile_size) in [((100, 60), (32, 32)), ((640, 320), (320, 320)), ((1000, 333), (64, 100))] {
+            let tiles = plan_tiles(image_size, tile_size, 0.25, 64);
+            assert!(!tiles.is_empty());
+            assert!(covered(&tiles, image_size.0, image_size.1));
+            assert!(tiles.iter().all(|tile| tile.left + tile.width <= image_size.0 && tile.top + tile.height <= image_size.1));
+        }
+    }
+
+    #[test]
+    fn tiles_grow_to_stay_within_the_budget() {
+        for max_tiles in [2, 5, 16] {
+            let tiles = plan_tiles((1000, 800), (32, 32), 0.25, max_tiles);
+            assert!(!tiles.is_empty() && tiles.len() <= max_tiles, "{} tiles for a budget of {}", tiles.len(), max_tiles);
+            assert!(tiles[0].width > 32);
+            assert!(covered(&tiles, 1000, 800));
+        }
+    }
+
+    #[test]
+    fn a_single_tile_is_not_worth_tiling() {
+        assert!(plan_tiles((100, 100), (320, 320), 0.25, 16).is_empty());
+        assert!(plan_tiles((1000, 1000), (32, 32), 0.25, 1).is_empty());
+    }
+
+    #[test]
+    fn overlapping_tiles_fade_into_each_other() {
+        let mut blender = TileBlender::new(64, 8, 4);
+        blender.add(&Tile { left: 0, top: 0, width: 40, height: 8 }, &GrayImage::from_pixel(40, 8, Luma([0])));
+        blender.add(&Tile { left: 24, top: 0, width: 40, height: 8 }, &GrayImage::from_pixel(40, 8, Luma([255])));
+        // a global mask which is undecided everywhere leaves it all to the tiles
+        let mask = blender.finish(&GrayImage::from_pixel(16, 2, Luma([128])));
+        let row: Vec<u8> = (0..64).map(|x| mask.get_pixel(x, 4)[0]).collect();
+        assert_eq!(row[0], 0);
+        assert_eq!(row[63], 255);
+        assert_eq!(row[32], 128);
+        assert!(row.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn tiles_only_decide_close_to_edges_of_the_global_mask() {
+        let mut blender = TileBlender::new(64, 8, 4);
+        blender.add(&Tile { left: 0, top: 0, width: 64, height: 8 }, &GrayImage::from_pixel(64, 8, Luma([128])));
+        let global = GrayImage::from_fn(64, 8, |x, _| Luma([if x < 32 { 0 } else { 255 }]));
+        let mask = blender.finish(&global);
+        assert_eq!(mask.get_pixel(0, 4)[0], 0);
+        assert_eq!(mask.get_pixel(63, 4)[0], 255);
+        assert_eq!(mask.get_pixel(32, 4)[0], 128);
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use image::GrayImage;
+
+use crate::utils::byte_lru::ByteLru;
+use crate::utils::models::ModelConfig;
+
+/// Identifies a segmentation: the source it ran on and everything that changes what the model sees.
+/// Options which are applied to the mask afterwards, like the activation function or the background, are not part of it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MaskKey {
+    /// SHA-256 of the source file, see `CachedImage::source_hash`
+    source: String,
+    model: u8,
+    preprocessing: u64,
+    /// tile budget of high detail segmentation, `None` for a single pass
+    tiles: Option<(usize, u32)>,
+}
+
+impl MaskKey {
+    pub fn new(source_hash: &str, model: &ModelConfig, tiles: Option<(usize, f32)>) -> Self {
+        let mut preprocessing = DefaultHasher::new();
+        (&model.file, model.width, model.height, model.output_index).hash(&mut preprocessing);
+        (model.resize as u8, model.channel_order as u8).hash(&mut preprocessing);
+        for value in model.mean.iter().chain(&model.std) {
+            value.to_bits().hash(&mut preprocessing);
+        }
+
+        MaskKey {
+            source: source_hash.to_owned(),
+            model: model.id,
+            preprocessing: preprocessing.finish(),
+            tiles: tiles.map(|(max_tiles, overlap)| (max_tiles, overlap.to_bits())),
+        }
+    }
+}
+
+/// Keeps the masks of recent segmentations, so that changing options which only affect
+/// the compositing doesn't run the model again. The least recently used masks are dropped
+/// once they take more than the given bytes.
+/// It is used from the blocking threads of the filters, hence the std mutex.
+pub struct MaskCache {
+    masks: Mutex<ByteLru<MaskKey, Arc<GrayImage>>>,
+}
+
+impl MaskCache {
+    pub fn new(max_bytes: usize) -> Self {
+        MaskCache { masks: Mutex::new(ByteLru::new(max_bytes)) }
+    }
+
+    pub fn get(&self, key: &MaskKey) -> Option<Arc<GrayImage>> {
+        self.masks.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: MaskKey, mask: Arc<GrayImage>) {
+        let size = mask.as_raw().len();
+        self.masks.lock().unwrap().insert(key, mask, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::models::ModelPurpose;
+
+    fn model() -> ModelConfig {
+        ModelConfig {
+            id: 1,
+            name: "General Use".to_owned(),
+            file: "isnet-general-use.onnx".to_owned(),
+            width: 1024,
+            height: 1024,
+            resize: Default::default(),
+            channel_order: Default::default(),
+            mean: [0.485, 0.456, 0.406],
+            std: [1., 1., 1.],
+            output_index: 0,
+            default_for: Some(ModelPurpose::General),
+        }
+    }
+
+    #[test]
+    fn key_changes_with_the_source_the_model_and_its_preprocessing() {
+        let key = MaskKey::new("abc", &model(), None);
+        assert_eq!(key, MaskKey::new("abc", &model(), None));
+        // the name is only the label of the button
+        assert_eq!(key, MaskKey::new("abc", &ModelConfig { name: "Renamed".to_owned(), ..model() }, None));
+        assert_ne!(key, MaskKey::new("abd", &model(), None));
+        assert_ne!(key, MaskKey::new("abc", &ModelConfig { id: 2, ..model() }, None));
+        assert_ne!(key, MaskKey::new("abc", &ModelConfig { width: 512, ..model() }, None));
+        assert_ne!(key, MaskKey::new("abc", &ModelConfig { mean: [0.5; 3], ..model() }, None));
+        assert_ne!(key, MaskKey::new("abc", &model(), Some((16, 0.25))));
+    }
+}
@@ -1,4 +1,14 @@
 pub mod image_cache;
+pub mod byte_lru;
+pub mod mask_cache;
+pub mod result_cache;
 pub mod colors;
+pub mod color_space;
+pub mod custom_id;
+pub mod id_store;
 pub mod image_processing;
-pub use image_processing::{generate_tp_image};
\ No newline at end of file
+pub mod palette;
+pub mod backgrounds;
+pub mod model_manager;
+pub mod models;
+pub use image_processing::{generate_tp_image};
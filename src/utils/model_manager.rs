@@ -0,0 +1,173 @@
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, warn};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use onnxruntime::ndarray::{Array4, ArrayD};
+use onnxruntime::session::Session;
+use onnxruntime::tensor::OrtOwnedTensor;
+use onnxruntime::{environment::Environment, GraphOptimizationLevel, LoggingLevel};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::InferenceConfig;
+use crate::utils::mask_cache::MaskCache;
+use crate::utils::models::{registry, ModelConfig};
+
+// onnxruntime sessions borrow their environment and can't be sent between threads.
+// Every worker therefore owns its environment and sessions, and the rest of the bot
+// only talks to the workers through a channel.
+
+struct Job {
+    model: ModelConfig,
+    input: Array4<f32>,
+    reply: Sender<Result<ArrayD<f32>>>,
+}
+
+/// Runs the background removal models on a fixed number of worker threads.
+/// Sessions are loaded on first use (or at startup with `warm_up`) and reused afterwards.
+/// Cloning is cheap, all clones share the same workers.
+#[derive(Clone)]
+pub struct ModelManager {
+    jobs: Sender<Job>,
+    max_tiles: usize,
+    tile_overlap: f32,
+    masks: Arc<MaskCache>,
+}
+
+impl ModelManager {
+    pub fn new(config: &InferenceConfig) -> Self {
+        let (jobs, receiver) = bounded::<Job>(config.queue_size);
+        let warm_up: Vec<ModelConfig> = if config.warm_up {
+            registry().models().to_vec()
+        } else {
+            Vec::new()
+        };
+        for worker_id in 0..config.workers.max(1) {
+            let receiver = receiver.clone();
+            let warm_up = warm_up.clone();
+            std::thread::Builder::new()
+                .name(format!("inference-{}", worker_id))
+                .spawn(move || worker(worker_id, receiver, warm_up))
+                .expect("Failed to spawn inference worker");
+        }
+        ModelManager {
+            jobs,
+            max_tiles: config.max_tiles,
+            tile_overlap: config.tile_overlap,
+            masks: Arc::new(MaskCache::new(config.mask_cache_mb * 1024 * 1024)),
+        }
+    }
+
+    /// Most tiles and their overlap for high detail segmentation
+    pub fn tile_budget(&self) -> (usize, f32) {
+        (self.max_tiles, self.tile_overlap)
+    }
+
+    /// Masks of recent segmentations
+    pub fn masks(&self) -> &MaskCache {
+        &self.masks
+    }
+
+    /// Runs `model` on the input tensor and returns its output at `model.output_index`.
+    /// Blocks until a worker is free, so it must not be called from async code.
+    pub fn run(&self, model: &ModelConfig, input: Array4<f32>) -> Result<ArrayD<f32>> {
+        self.submit(model, input)?.wait()
+    }
+
+    /// Queues `model` on the input tensor without waiting for the result, so that several inputs
+    /// can be spread over the workers from one thread. Blocks only while the queue is full.
+    pub fn submit(&self, model: &ModelConfig, input: Array4<f32>) -> Result<PendingRun> {
+        let (reply, response) = bounded(1);
+        self.jobs
+            .send(Job { model: model.clone(), input, reply })
+            .map_err(|_| anyhow!("All inference workers have stopped"))?;
+        Ok(PendingRun { model: model.name.clone(), response })
+    }
+}
+
+/// A job queued with [`ModelManager::submit`]
+pub struct PendingRun {
+    model: String,
+    response: Receiver<Result<ArrayD<f32>>>,
+}
+
+impl PendingRun {
+    /// Blocks until a worker ran the job, so it must not be called from async code
+    pub fn wait(self) -> Result<ArrayD<f32>> {
+        self.response
+            .recv()
+            .map_err(|_| anyhow!("The inference worker stopped while running {}", self.model))?
+    }
+}
+
+fn worker(worker_id: usize, jobs: Receiver<Job>, warm_up: Vec<ModelConfig>) {
+    let environment = match Environment::builder()
+        .with_name("background_removal")
+        .with_log_level(LoggingLevel::Warning)
+        .build()
+    {
+        Ok(environment) => environment,
+        Err(e) => {
+            let message = format!("Failed to create the onnxruntime environment: {}", e);
+            error!("[inference-{}] {}", worker_id, message);
+            for job in jobs.iter() {
+                let _ = job.reply.send(Err(anyhow!(message.clone())));
+            }
+            return;
+        }
+    };
+
+    // keyed by model id
+    let mut sessions: HashMap<u8, Session<'_>> = HashMap::new();
+    for model in warm_up {
+        match load_session(&environment, &model) {
+            Ok(session) => {
+                sessions.insert(model.id, session);
+            },
+            Err(e) => warn!("[inference-{}] {}", worker_id, e),
+        }
+    }
+
+    for job in jobs.iter() {
+        let start = std::time::Instant::now();
+        let result = run_job(&environment, &mut sessions, &job.model, job.input);
+        debug!("[inference-{}] {} took {:.3} seconds", worker_id, job.model.name, start.elapsed().as_secs_f32());
+        // the caller may have given up already
+        let _ = job.reply.send(result);
+    }
+}
+
+fn run_job<'e>(
+    environment: &'e Environment,
+    sessions: &mut HashMap<u8, Session<'e>>,
+    model: &ModelConfig,
+    input: Array4<f32>,
+) -> Result<ArrayD<f32>> {
+    let session = match sessions.entry(model.id) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(load_session(environment, model)?),
+    };
+    let outputs: Vec<OrtOwnedTensor<f32, _>> = session
+        .run(vec![input])
+        .map_err(|e| anyhow!("Failed to run {}: {}", model.name, e))?;
+    let Some(output) = outputs.get(model.output_index) else {
+        bail!("{} has no output {}, only {}", model.name, model.output_index, outputs.len());
+    };
+    Ok(output.view().to_owned())
+}
+
+fn load_session<'e>(environment: &'e Environment, model: &ModelConfig) -> Result<Session<'e>> {
+    let path = registry().path(model);
+    if !Path::new(&path).is_file() {
+        bail!("The model {} was not found at {}", model.name, path);
+    }
+    let start = std::time::Instant::now();
+    let session = environment
+        .new_session_builder()
+        .and_then(|builder| builder.with_optimization_level(GraphOptimizationLevel::Basic))
+        .and_then(|builder| builder.with_model_from_file(path.clone()))
+        .map_err(|e| anyhow!("Failed to load the model {} from {}: {}", model.name, path, e))?;
+    debug!("Loaded {} in {:.3} seconds", model.name, start.elapsed().as_secs_f32());
+    Ok(session)
+}
@@ -0,0 +1,189 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::config::load_config;
+use crate::utils::image_processing::ResizeMode;
+
+/// Order of the color channels a model expects
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Kind of image a model is picked for when options are adjusted automatically
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelPurpose {
+    General,
+    Anime,
+}
+
+fn default_mean() -> [f32; 3] {
+    [0.0; 3]
+}
+
+fn default_std() -> [f32; 3] {
+    [1.0; 3]
+}
+
+/// A background removal model, declared as `[[models]]` in `config.toml`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ModelConfig {
+    /// Stored in the buttons, so it should not change while buttons are in use. 0 is reserved.
+    pub id: u8,
+    /// Label of the button
+    pub name: String,
+    /// Path of the ONNX file, relative to `threshold.modelpath`
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+    /// How images are fitted into `width` x `height`
+    #[serde(default)]
+    pub resize: ResizeMode,
+    #[serde(default)]
+    pub channel_order: ChannelOrder,
+    /// Subtracted from each channel after scaling it to [0, 1]
+    #[serde(default = "default_mean")]
+    pub mean: [f32; 3],
+    /// Each channel is divided by it after subtracting the mean
+    #[serde(default = "default_std")]
+    pub std: [f32; 3],
+    /// Which output of the model holds the mask
+    #[serde(default)]
+    pub output_index: usize,
+    #[serde(default)]
+    pub default_for: Option<ModelPurpose>,
+}
+
+/// How the background is found: by erasing the most present color, with a configured model
+/// or with both combined
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BackgroundModel {
+    Algorithm,
+    Onnx(u8),
+    /// the configured model fused with the distance to the most present color
+    Hybrid(u8),
+}
+
+impl BackgroundModel {
+    /// The id used in custom ids. Unknown model ids are an error.
+    pub fn from_id(id: u8) -> Result<Self> {
+        if id == 0 {
+            return Ok(BackgroundModel::Algorithm);
+        }
+        registry().get(id)?;
+        Ok(BackgroundModel::Onnx(id))
+    }
+
+    /// The hybrid eraser with the model of `id`, which can't be the algorithm
+    pub fn hybrid_from_id(id: u8) -> Result<Self> {
+        if id == 0 {
+            bail!("The hybrid eraser needs a configured model");
+        }
+        registry().get(id)?;
+        Ok(BackgroundModel::Hybrid(id))
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            BackgroundModel::Algorithm => 0,
+            BackgroundModel::Onnx(id) | BackgroundModel::Hybrid(id) => *id,
+        }
+    }
+
+    /// The id of the configured model, if one is used
+    pub fn model_id(&self) -> Option<u8> {
+        match self {
+            BackgroundModel::Algorithm => None,
+            BackgroundModel::Onnx(id) | BackgroundModel::Hybrid(id) => Some(*id),
+        }
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        matches!(self, BackgroundModel::Hybrid(_))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            BackgroundModel::Algorithm => "Dominant Color".to_owned(),
+            BackgroundModel::Onnx(id) => registry()
+                .get(*id)
+                .map(|model| model.name.clone())
+                .unwrap_or_else(|_| format!("Model {}", id)),
+            BackgroundModel::Hybrid(_) => "Hybrid".to_owned(),
+        }
+    }
+
+    /// The model for `purpose`, falling back to the first configured model and then to the algorithm
+    pub fn for_purpose(purpose: ModelPurpose) -> Self {
+        let models = registry().models();
+        models
+            .iter()
+            .find(|model| model.default_for == Some(purpose))
+            .or(models.first())
+            .map_or(BackgroundModel::Algorithm, |model| BackgroundModel::Onnx(model.id))
+    }
+}
+
+/// All models from `config.toml`
+pub struct ModelRegistry {
+    models: Vec<ModelConfig>,
+    /// `threshold.modelpath`, which the files of the models are relative to
+    directory: String,
+}
+
+lazy_static! {
+    static ref REGISTRY: ModelRegistry = {
+        let config = load_config();
+        ModelRegistry::new(config.models, config.threshold.modelpath).expect("Invalid `[[models]]` in config.toml")
+    };
+}
+
+/// The models declared in `config.toml`, which is read once
+pub fn registry() -> &'static ModelRegistry {
+    &REGISTRY
+}
+
+impl ModelRegistry {
+    pub fn new(models: Vec<ModelConfig>, directory: String) -> Result<Self> {
+        for (index, model) in models.iter().enumerate() {
+            if model.id == 0 {
+                bail!("Model {} uses the id 0, which is reserved for the dominant color algorithm", model.name);
+            }
+            if models[..index].iter().any(|other| other.id == model.id) {
+                bail!("The model id {} is used more than once", model.id);
+            }
+            if model.width == 0 || model.height == 0 || model.std.contains(&0.0) {
+                bail!("Model {} needs a non-zero size and std", model.name);
+            }
+        }
+        Ok(ModelRegistry { models, directory })
+    }
+
+    /// Path of the ONNX file of the model
+    pub fn path(&self, model: &ModelConfig) -> String {
+        Path::new(&self.directory).join(&model.file).to_string_lossy().into_owned()
+    }
+
+    pub fn models(&self) -> &[ModelConfig] {
+        &self.models
+    }
+
+    pub fn get(&self, id: u8) -> Result<&ModelConfig> {
+        match self.models.iter().find(|model| model.id == id) {
+            Some(model) => Ok(model),
+            None => {
+                let configured: Vec<String> = self.models
+                    .iter()
+                    .map(|model| format!("{} ({})", model.id, model.name))
+                    .collect();
+                bail!("Unknown model id {}. Configured models: {}", id, configured.join(", "))
+            }
+        }
+    }
+}
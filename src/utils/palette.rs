@@ -0,0 +1,170 @@
+use anyhow::{bail, Result};
+use log::warn;
+use tokio::sync::RwLock;
+
+use crate::utils::colors::RgbColor;
+use crate::utils::id_store::IdStore;
+
+/// Ids below this belong to built-in themes, ids from here on to user-defined palettes
+pub const CUSTOM_PALETTE_START: u16 = 256;
+/// Reserved id of the "Custom Palette" button, which asks the user for a palette
+pub const PALETTE_REQUEST: u16 = u16::MAX;
+/// Custom palettes which are kept before the oldest ones are dropped
+const CUSTOM_PALETTE_CAPACITY: usize = 1000;
+/// Colors with a lower grayscale similarity than this are treated as contrast colors
+const CONTRAST_MAX_GRAYSCALE_SIMILARITY: f32 = 0.08;
+
+/// A color theme the nord filter can snap to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    /// Dark, mostly gray colors used for the unsaturated parts of an image
+    pub contrast: Vec<RgbColor>,
+    /// Colorful tones used for the saturated parts of an image
+    pub accent: Vec<RgbColor>,
+}
+
+/// Parses a list of hex codes which are known to be valid
+fn hex_list(colors: &[&str]) -> Vec<RgbColor> {
+    colors.iter().map(|hex| RgbColor::from_hex(hex).unwrap()).collect()
+}
+
+impl Palette {
+    fn new(name: &str, contrast: &[&str], accent: &[&str]) -> Self {
+        Palette {
+            name: name.to_owned(),
+            contrast: hex_list(contrast),
+            accent: hex_list(accent),
+        }
+    }
+
+    /// All built-in themes. The index of a theme is its id.
+    pub fn builtin() -> Vec<Palette> {
+        vec![
+            // Polar Night and Frost
+            Palette::new(
+                "Nord",
+                &["2e3440", "3b4252", "434c5e", "4c566a"],
+                &["8fbcbb", "88c0d0", "81a1c1", "5e81ac"],
+            ),
+            Palette::new(
+                "Dracula",
+                &["282a36", "343746", "44475a", "6272a4"],
+                &["8be9fd", "50fa7b", "ffb86c", "ff79c6", "bd93f9", "ff5555", "f1fa8c"],
+            ),
+            Palette::new(
+                "Gruvbox",
+                &["282828", "3c3836", "504945", "665c54"],
+                &["83a598", "8ec07c", "fabd2f", "fe8019", "d3869b", "fb4934", "b8bb26"],
+            ),
+            // Mocha flavour
+            Palette::new(
+                "Catppuccin",
+                &["1e1e2e", "313244", "45475a", "585b70"],
+                &["89b4fa", "94e2d5", "a6e3a1", "f9e2af", "fab387", "f38ba8", "cba6f7"],
+            ),
+            Palette::new(
+                "Solarized",
+                &["002b36", "073642", "586e75", "657b83"],
+                &["268bd2", "2aa198", "859900", "b58900", "cb4b16", "dc322f", "d33682", "6c71c4"],
+            ),
+            Palette::new(
+                "Tokyo Night",
+                &["1a1b26", "24283b", "414868", "565f89"],
+                &["7aa2f7", "7dcfff", "9ece6a", "e0af68", "ff9e64", "f7768e", "bb9af7"],
+            ),
+        ]
+    }
+
+    /// Builds a palette from user-given colors.
+    /// Colors before a `|` are contrast colors, the ones after it accents.
+    /// Without `|`, the colors are split by how gray they are.
+    pub fn from_hex_list(name: &str, hex_list: &str) -> Result<Palette> {
+        let (contrast, accent) = match hex_list.split_once('|') {
+            Some((contrast, accent)) => (RgbColor::from_hex_list(contrast)?, RgbColor::from_hex_list(accent)?),
+            None => {
+                let colors = RgbColor::from_hex_list(hex_list)?;
+                if colors.len() < 2 {
+                    bail!("A palette needs at least 2 colors, got {}", colors.len());
+                }
+                let (mut contrast, mut accent): (Vec<RgbColor>, Vec<RgbColor>) = colors
+                    .into_iter()
+                    .partition(|color| color.calculate_grayscale_similarity() < CONTRAST_MAX_GRAYSCALE_SIMILARITY);
+                if contrast.is_empty() {
+                    // no gray colors -> the darker half gives the contrast
+                    accent.sort_by(|a, b| a.brightness().total_cmp(&b.brightness()));
+                    contrast = accent.drain(..accent.len() / 2).collect();
+                }
+                if accent.is_empty() {
+                    accent = contrast.clone();
+                }
+                (contrast, accent)
+            }
+        };
+        if contrast.is_empty() || accent.is_empty() {
+            bail!("Both sides of `|` need at least one color");
+        }
+        Ok(Palette { name: name.to_owned(), contrast, accent })
+    }
+
+    /// Returns the built-in theme with the given id, falling back to Nord
+    pub fn by_id(id: u16) -> Palette {
+        let mut palettes = Palette::builtin();
+        if (id as usize) < palettes.len() {
+            palettes.swap_remove(id as usize)
+        } else {
+            palettes.swap_remove(0)
+        }
+    }
+
+    /// Name of the palette with the given id, without looking up custom palettes
+    pub fn name_of(id: u16) -> String {
+        if id >= CUSTOM_PALETTE_START {
+            "Custom".to_owned()
+        } else {
+            Palette::by_id(id).name
+        }
+    }
+
+    /// The id of the built-in theme which comes after `id`, wrapping around.
+    /// Custom palettes are followed by the first built-in theme.
+    pub fn next_id(id: u16) -> u16 {
+        ((id as usize + 1) % Palette::builtin().len()) as u16
+    }
+}
+
+/// Keeps the palettes users entered, so that buttons can refer to them by id.
+pub struct PaletteStore {
+    custom: RwLock<IdStore<Palette>>,
+}
+
+impl Default for PaletteStore {
+    fn default() -> Self {
+        PaletteStore { custom: RwLock::new(IdStore::new(CUSTOM_PALETTE_START..PALETTE_REQUEST, CUSTOM_PALETTE_CAPACITY)) }
+    }
+}
+
+impl PaletteStore {
+    /// Stores the palette and returns its id. Palettes with equal colors share one id.
+    pub async fn insert(&self, palette: Palette) -> u16 {
+        let mut custom = self.custom.write().await;
+        if let Some(id) = custom.find(|stored| *stored == palette) {
+            return id;
+        }
+        custom.insert(palette)
+    }
+
+    /// Returns the palette with the given id. Unknown ids fall back to Nord.
+    pub async fn get(&self, id: u16) -> Palette {
+        if id < CUSTOM_PALETTE_START {
+            return Palette::by_id(id);
+        }
+        match self.custom.read().await.get(id) {
+            Some(palette) => palette.clone(),
+            None => {
+                warn!("Custom palette {} is gone, falling back to Nord", id);
+                Palette::by_id(0)
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::storage::{calculate_sha256, Storage};
+use crate::utils::byte_lru::ByteLru;
+use crate::utils::colors::{NordOptions, RgbColor};
+use crate::utils::image_processing::OutputFormat;
+use crate::utils::palette::Palette;
+
+/// Bumped whenever a filter or the encoding changes its output, so that results of older versions aren't sent
+const RESULT_VERSION: u32 = 1;
+
+/// How often the directory is trimmed to `results.directory_mb`
+const DIRECTORY_EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Everything besides the options which changes results: the version of the bot, the pipelines of the presets,
+/// the models and the limits of animations. Results on disk and in the storage outlive a restart,
+/// so they must not be found again once one of these changed.
+pub fn fingerprint(config: &Config) -> String {
+    // sorted, since the order of a HashMap differs between runs
+    let presets: BTreeMap<_, _> = config.presets.iter().collect();
+    let fingerprint = format!(
+        "{}|{}|{:?}|{:?}|{:?}|{}",
+        env!("CARGO_PKG_VERSION"), RESULT_VERSION, presets, config.models, config.animation, config.threshold.modelpath,
+    );
+    calculate_sha256(fingerprint.as_bytes())
+}
+
+/// Identifies an encoded result: the source, every option that changes the result and the output settings
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResultKey {
+    hash: String,
+}
+
+impl ResultKey {
+    /// `source` is the SHA-256 of the source bytes and `fingerprint` the one of the configuration, see [`fingerprint`].
+    /// Custom palettes only live in memory and their ids are given out again after a restart,
+    /// so the colors of the palette are part of the key instead of its id.
+    pub fn new(fingerprint: &str, source: &str, options: &NordOptions, palette: &Palette, format: OutputFormat, quality: u8) -> Self {
+        let colors = |colors: &[RgbColor]| -> String {
+            colors.iter().map(|color| format!("{:02x}{:02x}{:02x}", color.r, color.g, color.b)).collect()
+        };
+        let canonical = format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            fingerprint, source, options.canonical_id(), colors(&palette.contrast), colors(&palette.accent), format as u8, quality,
+        );
+        ResultKey { hash: calculate_sha256(canonical.as_bytes()) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// Keeps encoded results, so that darkening the same image with the same options again skips the filters
+/// and the encoder. Results live in memory up to a byte budget, optionally in a directory on disk,
+/// whose oldest files are deleted once it grows beyond its budget, and in the configured storage,
+/// which deletes old results.
+pub struct ResultCache {
+    /// of the configuration the cache was created with, part of every key
+    fingerprint: String,
+    memory: Mutex<ByteLru<ResultKey, Arc<Vec<u8>>>>,
+    directory: Option<PathBuf>,
+    /// counter for the names of files which are still written
+    temporary: AtomicU64,
+    storage: Option<Storage>,
+}
+
+impl ResultCache {
+    pub fn new(config: &Config, storage: Option<Storage>) -> Self {
+        let directory = config.results.directory.as_ref().map(PathBuf::from);
+        if let Some(directory) = &directory {
+            if let Err(e) = std::fs::create_dir_all(directory) {
+                warn!("Failed to create the result cache at {}: {}", directory.display(), e);
+            }
+            if config.results.directory_mb > 0 {
+                spawn_directory_eviction(directory.clone(), config.results.directory_mb * 1024 * 1024);
+            }
+        }
+        ResultCache {
+            fingerprint: fingerprint(config),
+            memory: Mutex::new(ByteLru::new(config.results.memory_mb * 1024 * 1024)),
+            directory,
+            temporary: AtomicU64::new(0),
+            storage,
+        }
+    }
+
+    /// Key of the result of the source with the options
+    pub fn key(&self, source: &str, options: &NordOptions, palette: &Palette, format: OutputFormat, quality: u8) -> ResultKey {
+        ResultKey::new(&self.fingerprint, source, options, palette, format, quality)
+    }
+
+    /// The encoded result, from memory, from disk or from the storage
+    pub async fn get(&self, key: &ResultKey) -> Option<Arc<Vec<u8>>> {
+        if let Some(bytes) = self.memory.lock().await.get(key) {
+            return Some(bytes.clone());
+        }
+        let bytes = Arc::new(self.load(key).await?);
+        self.memory.lock().await.insert(key.clone(), bytes.clone(), bytes.len());
+        Some(bytes)
+    }
+
+    /// Looks for a result which isn't in memory
+    async fn load(&self, key: &ResultKey) -> Option<Vec<u8>> {
+        if let Some(directory) = &self.directory {
+            if let Ok(bytes) = tokio::fs::read(directory.join(&key.hash)).await {
+                return Some(bytes);
+            }
+        }
+        match self.storage.as_ref()?.results().get(key).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to read the result {} from the storage: {}", key.hash, e);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: ResultKey, bytes: Arc<Vec<u8>>) {
+        if let Some(directory) = &self.directory {
+            let path = directory.join(&key.hash);
+            if let Err(e) = self.write_file(directory, &path, &bytes).await {
+                warn!("Failed to write the result cache entry {}: {}", path.display(), e);
+            }
+        }
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.results().put(&key, &bytes).await {
+                warn!("Failed to write the result {} to the storage: {}", key.hash, e);
+            }
+        }
+        let size = bytes.len();
+        self.memory.lock().await.insert(key, bytes, size);
+    }
+
+    /// Writes the file under a temporary name and renames it, so that a crash or a concurrent `load`
+    /// never sees a partially written result
+    async fn write_file(&self, directory: &Path, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let number = self.temporary.fetch_add(1, Ordering::Relaxed);
+        let temporary = directory.join(format!(".{}.{}.tmp", std::process::id(), number));
+        if let Err(e) = tokio::fs::write(&temporary, bytes).await {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return Err(e);
+        }
+        if let Err(e) = tokio::fs::rename(&temporary, path).await {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Trims the directory to `max_bytes` right away and then every `DIRECTORY_EVICTION_INTERVAL`
+fn spawn_directory_eviction(directory: PathBuf, max_bytes: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DIRECTORY_EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let path = directory.clone();
+            let evicted = tokio::task::spawn_blocking(move || evict_directory(&path, max_bytes))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            match evicted {
+                Ok(0) => {},
+                Ok(deleted) => debug!("Deleted {} old results from {}", deleted, directory.display()),
+                Err(e) => warn!("Failed to delete old results from {}: {}", directory.display(), e),
+            }
+        }
+    });
+}
+
+/// Deletes the files of the directory which were written first until the others take at most `max_bytes`.
+/// Returns how many files were deleted.
+pub fn evict_directory(directory: &Path, max_bytes: u64) -> std::io::Result<usize> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(directory)?.flatten() {
+        // files may be renamed or deleted meanwhile, those are skipped, like files without a modification time
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        if let Ok(modified) = metadata.modified() {
+            files.push((modified, metadata.len(), entry.path()));
+        }
+    }
+    files.sort();
+    let mut bytes: u64 = files.iter().map(|(_, size, _)| size).sum();
+    let mut deleted = 0;
+    for (_, size, path) in files {
+        if bytes <= max_bytes {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => deleted += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        bytes -= size;
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn the_oldest_files_are_evicted_first() {
+        let directory = std::env::temp_dir().join(format!("midna-results-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("old", 30), ("middle", 20), ("new", 10)] {
+            let path = directory.join(name);
+            std::fs::write(&path, [0u8; 100]).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        assert_eq!(evict_directory(&directory, 300).unwrap(), 0);
+        assert_eq!(evict_directory(&directory, 150).unwrap(), 2);
+        assert!(directory.join("new").exists());
+        assert!(!directory.join("middle").exists() && !directory.join("old").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}